@@ -0,0 +1,79 @@
+/*written by kimikan, 2017-7-12*/
+//load for the echo example, every client pipelines its messages
+//from one thread and reads the echoes back from another.
+//run the echo server in release first, then:
+//  cargo run --release --example bench_client -- 4 50000 32
+//args: clients, messages a client, message size, [addr]
+extern crate byteorder;
+
+use std::env;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process;
+use std::thread;
+use std::time::Instant;
+
+use byteorder::{ByteOrder, BigEndian};
+
+//the writes are batched to about this much
+const WRITE_BATCH: usize = 64 * 1024;
+
+fn client(addr: &str, messages: usize, size: usize) {
+    let mut stream = TcpStream::connect(addr).expect("connect failed");
+    let mut reader = stream.try_clone().unwrap();
+
+    let writer = thread::spawn(move || {
+        let mut header = [0u8; 8];
+        BigEndian::write_u64(&mut header, size as u64);
+        let body = vec![7u8; size];
+        let mut buf = vec![];
+        for _ in 0..messages {
+            buf.extend_from_slice(&header);
+            buf.extend_from_slice(&body);
+            if buf.len() > WRITE_BATCH {
+                stream.write_all(&buf).unwrap();
+                buf.clear();
+            }
+        }
+        stream.write_all(&buf).unwrap();
+    });
+
+    //the echoes come back framed the same way
+    let total = messages * (size + 8);
+    let mut got = 0;
+    let mut buf = vec![0u8; 64 * 1024];
+    while got < total {
+        let n = reader.read(&mut buf).unwrap();
+        assert!(n > 0, "server closed after {} of {} bytes", got, total);
+        got += n;
+    }
+    writer.join().unwrap();
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 3 {
+        eprintln!("usage: bench_client <clients> <messages> <size> [addr]");
+        process::exit(1);
+    }
+    let parse = |s: &String| s.parse::<usize>().unwrap_or_else(|e| {
+        eprintln!("bad number {}: {}", s, e);
+        process::exit(1);
+    });
+    let (clients, messages, size) = (parse(&args[0]), parse(&args[1]), parse(&args[2]));
+    let addr = args.get(3).cloned().unwrap_or_else(|| "127.0.0.1:7777".to_string());
+
+    let start = Instant::now();
+    let handles: Vec<_> = (0..clients).map(|_| {
+        let addr = addr.clone();
+        thread::spawn(move || client(&addr, messages, size))
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+    let total = (clients * messages) as f64;
+    println!("{} clients x {} x {}B: {:.0} msg/s, {:.1} MB/s",
+             clients, messages, size, total / elapsed, total * size as f64 / elapsed / 1e6);
+}
@@ -0,0 +1,218 @@
+
+/*written by kimikan, 2017-7-12*/
+//the echo server, every message is sent back to its sender.
+//SIGTERM or SIGINT shuts it down gracefully, SIGHUP reloads
+//the settings file given as the first argument
+extern crate miox;
+extern crate libc;
+extern crate log;
+
+use std::env;
+use std::fs;
+use std::mem;
+use std::process;
+use std::ptr;
+use std::thread;
+use std::time::Duration;
+use miox::{MessageHandler, HandlerContext, HandlerAction, DisconnectReason, RawCodec, ServerBuilder};
+
+struct EchoHandler {
+    //nop
+}
+
+impl EchoHandler {
+    fn new() -> EchoHandler {
+        EchoHandler {}
+    }
+}
+
+//how many messages a client echoed
+#[derive(Default)]
+struct EchoSession {
+    _messages: usize,
+}
+
+impl MessageHandler for EchoHandler {
+    type Session = EchoSession;
+    type Codec = RawCodec;
+
+    fn on_connected(&self, ctx: &HandlerContext<Self>) {
+        println!("echo: client connected {:?}, {:?}", ctx.id(), ctx.peer_addr());
+    }
+
+    fn on_disconnected(&self, ctx: &HandlerContext<Self>, reason: DisconnectReason) {
+        println!("echo: client disconnected {:?}, {:?}, {} messages",
+                 ctx.id(), reason, ctx.connection().session()._messages);
+    }
+
+    fn on_message_received(&self, ctx: &HandlerContext<Self>, message: Vec<u8>) -> miox::Result<HandlerAction> {
+        ctx.connection().session()._messages += 1;
+        ctx.send(message)?;
+        Ok(HandlerAction::Continue)
+    }
+}
+
+//the library logs through the log crate, the worker lifecycle
+//and the errors go to stderr, the per-event detail is dropped
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{} {}: {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/* main usage */
+/*
+ * the settings SIGHUP reloads, one "key = value" a line:
+ *  max_clients = 100
+ *  shutdown_timeout = 5
+ * the listen address and the workers need a restart
+ */
+struct Settings {
+    _max_clients: usize,
+    //seconds the clients get to go on shutdown
+    _shutdown_timeout: Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            _max_clients: usize::MAX,
+            _shutdown_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Settings {
+    //none if the file can't be read, a bad line is skipped
+    fn load(path: &str) -> Option<Settings> {
+        let text = match fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("echo: can't read {}: {}", path, e);
+                return None;
+            }
+        };
+
+        let mut settings = Settings::default();
+        for line in text.lines() {
+            let mut kv = line.splitn(2, '=').map(str::trim);
+            match (kv.next(), kv.next()) {
+                (Some("max_clients"), Some(v)) => match v.parse() {
+                    Ok(n) => settings._max_clients = n,
+                    Err(e) => eprintln!("echo: bad max_clients {}: {}", v, e),
+                },
+                (Some("shutdown_timeout"), Some(v)) => match v.parse() {
+                    Ok(n) => settings._shutdown_timeout = Duration::from_secs(n),
+                    Err(e) => eprintln!("echo: bad shutdown_timeout {}: {}", v, e),
+                },
+                _ => {}
+            }
+        }
+        Some(settings)
+    }
+}
+
+//block the signals for this thread and the ones it starts
+//later, so they are only taken by wait_signal
+fn block_signals(signals: &[libc::c_int]) -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for s in signals {
+            libc::sigaddset(&mut set, *s);
+        }
+        let r = libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+        assert_eq!(r, 0, "pthread_sigmask failed");
+        set
+    }
+}
+
+fn wait_signal(set: &libc::sigset_t) -> libc::c_int {
+    let mut signal = 0;
+    let r = unsafe { libc::sigwait(set, &mut signal) };
+    assert_eq!(r, 0, "sigwait failed");
+    signal
+}
+
+/*
+ * exit status:
+ *  0 every client closed in time
+ *  1 the server failed
+ *  2 some clients were still connected at the shutdown timeout
+ */
+fn main() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(log::LevelFilter::Info);
+    }
+    let path = env::args().nth(1);
+    let mut settings = match path {
+        Some(ref p) => Settings::load(p).unwrap_or_else(|| process::exit(1)),
+        None => Settings::default(),
+    };
+
+    //before the workers start, they inherit the mask
+    let signals = block_signals(&[libc::SIGTERM, libc::SIGINT, libc::SIGHUP]);
+
+    let server = ServerBuilder::new(EchoHandler::new(), RawCodec)
+        .bind("127.0.0.1:7777")
+        .with_workers(3)
+        .with_max_clients_per_worker(127)
+        .with_max_clients(settings._max_clients)
+        .run()
+        .unwrap_or_else(|e| {
+            eprintln!("echo: server run failed: {}", e);
+            process::exit(1);
+        });
+
+    //the signals are taken on their own thread while main joins,
+    //a second SIGTERM or SIGINT closes whatever is left right away
+    let context = server.context().clone();
+    thread::spawn(move || {
+        let mut shutting_down = false;
+        loop {
+            match wait_signal(&signals) {
+                libc::SIGHUP => {
+                    let reloaded = path.as_ref().and_then(|p| Settings::load(p));
+                    if let Some(s) = reloaded {
+                        context.set_max_total_clients(s._max_clients);
+                        settings = s;
+                        println!("echo: settings reloaded");
+                    }
+                }
+                signal if shutting_down => {
+                    println!("echo: signal {} again, closing now", signal);
+                    context.shutdown(Duration::from_secs(0));
+                }
+                signal => {
+                    println!("echo: signal {}, shutting down", signal);
+                    context.shutdown(settings._shutdown_timeout);
+                    shutting_down = true;
+                }
+            }
+        }
+    });
+
+    let code = match server.join() {
+        Ok(stats) => {
+            println!("app exit! {:?}", stats);
+            if stats.forced_closes() == 0 { 0 } else { 2 }
+        }
+        Err(e) => {
+            eprintln!("echo: server failed: {}", e);
+            1
+        }
+    };
+    process::exit(code);
+}
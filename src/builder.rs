@@ -0,0 +1,280 @@
+/*written by kimikan, 2017-7-12*/
+use std::io;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+use connection::{self, SocketOptions, Watermarks};
+use context::{self, Context};
+use error::{Error, Result};
+use framing::LengthDelimited;
+use serialize::MessageHandler;
+use server::{self, OversizePolicy, Server, ServerStats};
+
+//where the server listens if no address is bound
+pub const DEFAULT_ADDR: &str = "127.0.0.1:7777";
+pub const DEFAULT_WORKERS: usize = 3;
+pub const DEFAULT_MAX_CLIENTS_PER_WORKER: usize = 127;
+
+/*
+ * sets up the listeners, the workers and the limits of a
+ * server, then run starts a thread per worker. every worker
+ * accepts from every listener, every listener has its own framing.
+ * the defaults are one listener on DEFAULT_ADDR, 3 workers
+ * of 127 clients each, and no global limit
+ */
+pub struct ServerBuilder<T: MessageHandler + Sized> {
+    _handler: T,
+    _codec: T::Codec,
+    //none takes the default framing
+    _addrs: Vec<(String, Option<LengthDelimited>)>,
+    _workers: usize,
+    _max_clients_per_worker: usize,
+    _max_clients: usize,
+    _events_capacity: usize,
+    _poll_timeout: Option<Duration>,
+    _framing: LengthDelimited,
+    _oversize_policy: OversizePolicy,
+    _watermarks: Watermarks,
+    _coalesce_delay: Option<Duration>,
+    _linger_timeout: Duration,
+    _socket_options: SocketOptions,
+}
+
+impl<T: MessageHandler + Sized> ServerBuilder<T> {
+    pub fn new(handler: T, codec: T::Codec) -> Self {
+        ServerBuilder {
+            _handler: handler,
+            _codec: codec,
+            _addrs: vec![],
+            _workers: DEFAULT_WORKERS,
+            _max_clients_per_worker: DEFAULT_MAX_CLIENTS_PER_WORKER,
+            _max_clients: usize::MAX,
+            _events_capacity: server::EVENTS_CAPACITY,
+            _poll_timeout: None,
+            _framing: LengthDelimited::default(),
+            _oversize_policy: OversizePolicy::Close,
+            _watermarks: Watermarks::default(),
+            _coalesce_delay: None,
+            _linger_timeout: server::LINGER_TIMEOUT,
+            _socket_options: SocketOptions::default(),
+        }
+    }
+
+    //listen on one more address, it's parsed by run
+    pub fn bind(mut self, addr: &str) -> Self {
+        self._addrs.push((addr.to_string(), None));
+        self
+    }
+
+    //the clients of this address use the framing,
+    //instead of the one of with_framing
+    pub fn bind_with_framing(mut self, addr: &str, framing: LengthDelimited) -> Self {
+        self._addrs.push((addr.to_string(), Some(framing)));
+        self
+    }
+
+    //run fails with no worker
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self._workers = workers;
+        self
+    }
+
+    //run fails above connection::MAX_CLIENTS
+    pub fn with_max_clients_per_worker(mut self, max: usize) -> Self {
+        self._max_clients_per_worker = max;
+        self
+    }
+
+    //of all of the workers together
+    pub fn with_max_clients(mut self, max: usize) -> Self {
+        self._max_clients = max;
+        self
+    }
+
+    //how many events a worker takes a poll
+    pub fn with_events_capacity(mut self, capacity: usize) -> Self {
+        self._events_capacity = capacity;
+        self
+    }
+
+    //the workers wake up at least this often
+    pub fn with_poll_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self._poll_timeout = timeout;
+        self
+    }
+
+    //of the addresses bound without a framing
+    pub fn with_framing(mut self, framing: LengthDelimited) -> Self {
+        self._framing = framing;
+        self
+    }
+
+    pub fn with_oversize_policy(mut self, policy: OversizePolicy) -> Self {
+        self._oversize_policy = policy;
+        self
+    }
+
+    pub fn with_watermarks(mut self, watermarks: Watermarks) -> Self {
+        self._watermarks = watermarks;
+        self
+    }
+
+    pub fn with_coalesce_delay(mut self, delay: Option<Duration>) -> Self {
+        self._coalesce_delay = delay;
+        self
+    }
+
+    pub fn with_linger_timeout(mut self, timeout: Duration) -> Self {
+        self._linger_timeout = timeout;
+        self
+    }
+
+    pub fn with_socket_options(mut self, options: SocketOptions) -> Self {
+        self._socket_options = options;
+        self
+    }
+}
+
+impl<T> ServerBuilder<T>
+    where T: MessageHandler + Sized + Send + Sync + 'static,
+          T::Codec: Send + Sync + 'static
+{
+    //binds the listeners and starts the workers,
+    //the settings are checked first
+    pub fn run(self) -> Result<ServerHandle<T>> {
+        if self._workers == 0 {
+            return Err(Error::InvalidConfig("no worker"));
+        }
+        if self._max_clients_per_worker > connection::MAX_CLIENTS {
+            return Err(Error::InvalidConfig("too many clients per worker"));
+        }
+
+        let mut addrs = self._addrs;
+        if addrs.is_empty() {
+            addrs.push((DEFAULT_ADDR.to_string(), None));
+        }
+
+        let mut listeners = vec![];
+        for (addr, _) in &addrs {
+            listeners.push(context::bind(addr)?);
+        }
+        let local_addrs = listeners.iter()
+            .map(|l| l.local_addr())
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let ctx = Context::new(self._handler, self._codec, self._max_clients_per_worker)?
+            .with_max_total_clients(self._max_clients);
+
+        let mut workers = vec![];
+        for i in 0..self._workers {
+            let clones = listeners.iter()
+                .map(|l| l.try_clone())
+                .collect::<io::Result<Vec<_>>>()?;
+            let mut server = Server::new(clones, &ctx)?;
+            server.set_events_capacity(self._events_capacity);
+            server.set_poll_timeout(self._poll_timeout);
+            for (index, (_, framing)) in addrs.iter().enumerate() {
+                server.set_framing(index, framing.unwrap_or(self._framing))?;
+            }
+            server.set_oversize_policy(self._oversize_policy.clone());
+            server.set_watermarks(self._watermarks);
+            server.set_coalesce_delay(self._coalesce_delay);
+            server.set_linger_timeout(self._linger_timeout);
+            server.set_socket_options(self._socket_options);
+
+            let ctx = ctx.clone();
+            let worker = thread::Builder::new()
+                .name(format!("miox-worker-{}", i))
+                .spawn(move || server.run(&ctx))?;
+            workers.push(worker);
+        }
+
+        Ok(ServerHandle {
+            _context: ctx,
+            _local_addrs: local_addrs,
+            _workers: workers,
+        })
+    }
+}
+
+/*
+ * a running server, the context reaches its clients
+ * from any thread. shutdown it from any thread, then
+ * join to wait for the workers
+ */
+pub struct ServerHandle<T: MessageHandler + Sized> {
+    _context: Context<T>,
+    _local_addrs: Vec<SocketAddr>,
+    _workers: Vec<thread::JoinHandle<Result<ServerStats>>>,
+}
+
+impl<T: MessageHandler + Sized> ServerHandle<T> {
+    pub fn context(&self) -> &Context<T> {
+        &self._context
+    }
+
+    //the bound addresses, the real port if 0 was bound
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self._local_addrs
+    }
+
+    //see Context::shutdown
+    pub fn shutdown(&self, timeout: Duration) {
+        self._context.shutdown(timeout);
+    }
+
+    //waits for all of the workers, returns the stats of them
+    //all, or the first error
+    pub fn join(self) -> Result<ServerStats> {
+        let mut result = Ok(ServerStats::default());
+        for worker in self._workers {
+            let r = match worker.join() {
+                Ok(r) => r,
+                Err(_) => Err(Error::handler("worker panicked")),
+            };
+            match (r, &mut result) {
+                (Ok(stats), Ok(total)) => total.merge(&stats),
+                (Ok(_), Err(_)) => {}
+                (Err(e), result) => {
+                    error!("worker exited: {}", e);
+                    if result.is_ok() {
+                        *result = Err(e);
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use context::HandlerContext;
+    use serialize::{HandlerAction, RawCodec};
+
+    struct Nop;
+
+    impl MessageHandler for Nop {
+        type Session = ();
+        type Codec = RawCodec;
+
+        fn on_message_received(&self, _: &HandlerContext<Self>, _: Vec<u8>) -> Result<HandlerAction> {
+            Ok(HandlerAction::Continue)
+        }
+    }
+
+    fn invalid(r: Result<ServerHandle<Nop>>) -> bool {
+        matches!(r, Err(Error::InvalidConfig(_)))
+    }
+
+    //checked before anything is bound
+    #[test]
+    fn invalid_settings_fail_run() {
+        assert!(invalid(ServerBuilder::new(Nop, RawCodec).with_workers(0).run()));
+        assert!(invalid(ServerBuilder::new(Nop, RawCodec)
+            .with_max_clients_per_worker(connection::MAX_CLIENTS + 1)
+            .run()));
+    }
+}
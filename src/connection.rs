@@ -2,214 +2,1074 @@
 /*written by kimikan, 2017-7-12*/
 use mio::Token;
 use mio::net::TcpStream;
+use iovec::IoVec;
 
-use byteorder::{ByteOrder, BigEndian};
+use framing::{LengthDelimited, MAX_HEADER_LEN};
 use poll;
+use serialize;
+use serialize::MessageCodec;
+use error::{Error, Result};
 
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, RwLock, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
+use std::collections::VecDeque;
 use std::io;
-use std::io::{Write, Read, Error, ErrorKind};
+use std::io::{Read, ErrorKind};
+use std::net::{SocketAddr, Shutdown};
+use std::cmp;
+use std::convert::TryFrom;
+use std::time::Duration;
 
-/* a client with an cnn*/
-pub struct Connection {
+//what a read round got
+pub enum Inbound<I> {
+    Message(I),
+    //a message longer than the framing's max frame len,
+    //its body is skipped, never buffered
+    Oversized(usize),
+}
+
+/*
+ * an outbound frame, taken out of the send queue.
+ * offset counts the header bytes first, then the body bytes
+ */
+struct WriteState {
+    header: [u8; MAX_HEADER_LEN],
+    header_len: usize,
+    body: Arc<Vec<u8>>,
+    offset: usize,
+}
+
+impl WriteState {
+    fn new(body: Arc<Vec<u8>>, framing: &LengthDelimited) -> Result<WriteState> {
+        let mut header = [0u8; MAX_HEADER_LEN];
+        let header_len = framing.encode_header(body.len(), &mut header)?;
+        Ok(WriteState {
+            header,
+            header_len,
+            body,
+            offset: 0,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.header_len + self.body.len()
+    }
+
+    //the unsent part of the header and of the body
+    fn remaining(&self) -> (&[u8], &[u8]) {
+        if self.offset < self.header_len {
+            (&self.header[self.offset..self.header_len], &self.body[..])
+        } else {
+            (&[], &self.body[self.offset - self.header_len..])
+        }
+    }
+}
+
+//n bytes are sent, drop the frames sent completely.
+//returns the bytes and the frames dropped
+fn consume_written(writing: &mut VecDeque<WriteState>, mut n: usize) -> (usize, usize) {
+    let (mut bytes, mut frames) = (0, 0);
+    while n > 0 {
+        let state = match writing.front_mut() {
+            Some(state) => state,
+            None => break,
+        };
+        let left = state.len() - state.offset;
+        if n < left {
+            state.offset += n;
+            break;
+        }
+        n -= left;
+        bytes += state.len();
+        frames += 1;
+        writing.pop_front();
+    }
+    (bytes, frames)
+}
+
+//slices gathered by one writev, a header and a body a frame
+const MAX_IOVECS: usize = 64;
+
+/*
+ * bounds of the unsent frames of a connection,
+ * reading from the client stops above either high watermark,
+ * and goes on once it's below both low watermarks
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watermarks {
+    _high_bytes: usize,
+    _low_bytes: usize,
+    _high_messages: usize,
+    _low_messages: usize,
+}
+
+impl Default for Watermarks {
+    fn default() -> Watermarks {
+        Watermarks::new(8 * 1024 * 1024, 1024 * 1024)
+            .with_messages(8192, 1024)
+    }
+}
+
+impl Watermarks {
+    pub fn new(high_bytes: usize, low_bytes: usize) -> Watermarks {
+        Watermarks {
+            _high_bytes: high_bytes,
+            _low_bytes: low_bytes,
+            _high_messages: usize::MAX,
+            _low_messages: usize::MAX,
+        }
+    }
+
+    pub fn with_messages(mut self, high: usize, low: usize) -> Watermarks {
+        self._high_messages = high;
+        self._low_messages = low;
+        self
+    }
+}
+
+/*
+ * set on every accepted client, none leaves the
+ * os default as it is
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    _nodelay: Option<bool>,
+    _keepalive: Option<Option<Duration>>,
+    _send_buffer_size: Option<usize>,
+    _recv_buffer_size: Option<usize>,
+}
+
+impl SocketOptions {
+    pub fn with_nodelay(mut self, nodelay: bool) -> SocketOptions {
+        self._nodelay = Some(nodelay);
+        self
+    }
+
+    //none turns keepalive off
+    pub fn with_keepalive(mut self, keepalive: Option<Duration>) -> SocketOptions {
+        self._keepalive = Some(keepalive);
+        self
+    }
+
+    pub fn with_send_buffer_size(mut self, size: usize) -> SocketOptions {
+        self._send_buffer_size = Some(size);
+        self
+    }
+
+    pub fn with_recv_buffer_size(mut self, size: usize) -> SocketOptions {
+        self._recv_buffer_size = Some(size);
+        self
+    }
+
+    pub(crate) fn apply(&self, stream: &TcpStream) -> Result<()> {
+        if let Some(nodelay) = self._nodelay {
+            stream.set_nodelay(nodelay)?;
+        }
+        if let Some(keepalive) = self._keepalive {
+            stream.set_keepalive(keepalive)?;
+        }
+        if let Some(size) = self._send_buffer_size {
+            stream.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self._recv_buffer_size {
+            stream.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+//the low bits of a token is the slab slot,
+//so at most 1 << SLOT_BITS clients a worker
+const SLOT_BITS: usize = 20;
+const SLOT_MASK: usize = (1 << SLOT_BITS) - 1;
+//the high bits is the generation of the slot,
+//the top bit is left for the server's own tokens
+const GENERATION_MASK: usize = usize::MAX >> (SLOT_BITS + 1);
+
+pub const MAX_CLIENTS: usize = 1 << SLOT_BITS;
+
+//the slab slot a client token points to
+pub(crate) fn slot_of(token: Token) -> usize {
+    token.0 & SLOT_MASK
+}
+
+/*
+ * tells a client among all of the workers.
+ * a slot is reused as soon as its client is removed,
+ * so the token carries the slot's generation as well,
+ * an id of a removed client never matches the new one
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ConnectionId {
+    _worker: usize,
     _token: Token,
-    pub _stream: TcpStream,
+}
 
-    //cache the send message between events
-    _send_queue: RwLock<Vec<Arc<Vec<u8>>>>,
-    _read_next: usize,
-    _write_next: bool,
+impl ConnectionId {
+    pub(crate) fn new(worker: usize, slot: usize, generation: usize) -> ConnectionId {
+        ConnectionId {
+            _worker: worker,
+            _token: Token(((generation & GENERATION_MASK) << SLOT_BITS) | (slot & SLOT_MASK)),
+        }
+    }
+
+    //the id of a token registered by the worker
+    pub(crate) fn from_token(worker: usize, token: Token) -> ConnectionId {
+        ConnectionId {
+            _worker: worker,
+            _token: token,
+        }
+    }
+
+    //the generation a slot gets after its client is removed
+    pub(crate) fn next_generation(generation: usize) -> usize {
+        generation.wrapping_add(1) & GENERATION_MASK
+    }
+
+    pub fn worker(&self) -> usize {
+        self._worker
+    }
+
+    pub fn slot(&self) -> usize {
+        slot_of(self._token)
+    }
+
+    pub fn generation(&self) -> usize {
+        self._token.0 >> SLOT_BITS
+    }
+
+    //the token registered into the worker's poller
+    pub fn token(&self) -> Token {
+        self._token
+    }
 }
 
-impl Connection {
+//the read buffer size, it only grows for a bigger message,
+//and shrinks back after it
+const READ_BUF_SIZE: usize = 64 * 1024;
+
+/*
+ * message =|message len| message buffer|
+ * the received bytes are buf[start..end].
+ * one read takes as much as the socket has, then every
+ * whole message in it is handed out before reading again,
+ * a message split by any number of wouldblocks just
+ * stays in the buffer until the rest arrives
+ */
+struct ReadBuffer {
+    buf: Vec<u8>,
+    start: usize,
+    end: usize,
+    //the bytes left of an oversized message, dropped as they come
+    skip: u64,
+}
+
+impl ReadBuffer {
+    fn new() -> ReadBuffer {
+        ReadBuffer {
+            buf: vec![],
+            start: 0,
+            end: 0,
+            skip: 0,
+        }
+    }
+
+    /*
+     * slices the next whole message out of the buffer,
+     * Some((start, len)) is the body in buf.
+     * None means more bytes are needed
+     */
+    fn next_frame(&mut self, framing: &LengthDelimited) -> Result<Option<Inbound<(usize, usize)>>> {
+        if self.skip > 0 {
+            let n = cmp::min(self.skip, (self.end - self.start) as u64);
+            self.start += n as usize;
+            self.skip -= n;
+            if self.skip > 0 {
+                return Ok(None);
+            }
+        }
+
+        let buffered = self.end - self.start;
+        match framing.decode_header(&self.buf[self.start..self.end])? {
+            None => Ok(None),
+            //don't trust the peer with the allocation
+            Some((header_len, len)) if len > framing.max_frame_len() as u64 => {
+                self.start += header_len;
+                self.skip = len;
+                //the handler gets usize::MAX for a len past it
+                let len = usize::try_from(len).unwrap_or(usize::MAX);
+                Ok(Some(Inbound::Oversized(len)))
+            }
+            //not above the max frame len, so it fits usize
+            Some((header_len, len)) if buffered < header_len + len as usize => {
+                //make sure the rest of it fits
+                self.reserve(header_len + len as usize);
+                Ok(None)
+            }
+            Some((header_len, len)) => {
+                let len = len as usize;
+                let start = self.start + header_len;
+                self.start = start + len;
+                Ok(Some(Inbound::Message((start, len))))
+            }
+        }
+    }
+
+    //room for a wanted bytes message from start
+    fn reserve(&mut self, wanted: usize) {
+        if self.buf.len() - self.start >= wanted {
+            return;
+        }
+
+        //move the received part to the front first,
+        //only the part never used before is zeroed
+        self.buf.copy_within(self.start..self.end, 0);
+        self.end -= self.start;
+        self.start = 0;
+        if self.buf.len() < wanted {
+            self.buf.resize(wanted, 0);
+        }
+    }
+
+    //drop whatever is buffered, the connection is closing
+    fn discard(&mut self) {
+        self.start = self.end;
+        self.skip = 0;
+    }
+
+    //a huge message doesn't pin its buffer forever
+    fn shrink(&mut self) {
+        if self.start == self.end && self.buf.len() > READ_BUF_SIZE {
+            self.start = 0;
+            self.end = 0;
+            self.buf.truncate(READ_BUF_SIZE);
+            self.buf.shrink_to_fit();
+        }
+    }
+
+    /*
+     * one read into the free part of the buffer,
+     * true means got some bytes, false means wouldblock.
+     * it's not a error case, should not close socket.
+     */
+    fn fill(&mut self, mut stream: &TcpStream) -> Result<bool> {
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+        }
+        if self.buf.len() < READ_BUF_SIZE {
+            self.buf.resize(READ_BUF_SIZE, 0);
+        }
+        if self.end == self.buf.len() {
+            //a header can't be longer than the buffer,
+            //so moving it to the front always makes room
+            let wanted = self.end - self.start + 1;
+            self.reserve(wanted);
+        }
+
+        loop {
+            match stream.read(&mut self.buf[self.end..]) {
+                Ok(0) => {
+                    //a part of a message is left, it's truncated
+                    if self.start != self.end || self.skip > 0 {
+                        return Err(Error::Framing("connection closed in the middle of a message"));
+                    }
+                    return Err(Error::Io(ErrorKind::UnexpectedEof.into()));
+                }
+                Ok(n) => {
+                    self.end += n;
+                    return Ok(true);
+                }
+                Err(e) => {
+                    if e.kind() == ErrorKind::WouldBlock {
+                        return Ok(false);
+                    } else if e.kind() != ErrorKind::Interrupted {
+                        return Err(Error::Io(e));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/* a client with an cnn*/
+pub struct Connection<T: serialize::MessageHandler> {
+    _id: ConnectionId,
+    pub(crate) _stream: TcpStream,
+
+    //the owner worker's notifier, to flush
+    //messages queued from any thread
+    _notifier: poll::Notifier,
+
+    /*
+     * the connection is shared by Arc, so the handler runs
+     * without any lock of the worker. the read and write
+     * sides are only used by the owner worker, the locks
+     * are never contended
+     */
+    _reader: Mutex<ReadBuffer>,
+
+    //cache the send message between events,
+    //fifo, the front one is the next to be sent
+    _send_queue: RwLock<VecDeque<Arc<Vec<u8>>>>,
+    //the frames being written, only touched by the owner worker,
+    //the front one may be partially sent
+    _writing: Mutex<VecDeque<WriteState>>,
+    //the unsent frames, queued or being written,
+    //counted by the sender, uncounted once written
+    _pending_bytes: AtomicUsize,
+    _pending_messages: AtomicUsize,
+    _watermarks: Watermarks,
+    //reading stopped by the watermarks
+    _backpressured: AtomicBool,
+    //asked to be closed, by any thread
+    _close_requested: AtomicBool,
+    //reading stopped by the handler, resumed tells the
+    //owner worker to read what came meanwhile
+    _paused: AtomicBool,
+    _resumed: AtomicBool,
+    /*
+     * the graceful close, see close_gracefully.
+     * closing: no more message is handled or queued, the queue is flushed.
+     * close_started: closing, not seen by the worker yet.
+     * write_shutdown: flushed, the fin is sent.
+     * read_eof: the peer's fin came
+     */
+    _closing: AtomicBool,
+    _close_started: AtomicBool,
+    _write_shutdown: AtomicBool,
+    _read_eof: AtomicBool,
+
+    //the handler's state of this client
+    _session: Mutex<T::Session>,
+    //shared by all of the clients
+    _codec: Arc<T::Codec>,
+    //the length prefix of the listener
+    _framing: LengthDelimited,
+}
+
+impl<T: serialize::MessageHandler> Connection<T> {
     /*
      * a connection means a tcpstream,  
      * and the token needed by the mio must be unique 
      * it 's managed by the server context.
      */
-    pub fn new(stream: TcpStream, token: Token) -> Connection {
+    pub(crate) fn new(stream: TcpStream, id: ConnectionId, notifier: poll::Notifier,
+               codec: Arc<T::Codec>, framing: LengthDelimited,
+               watermarks: Watermarks) -> Connection<T> {
         Connection {
-            _token: token,
+            _id: id,
             _stream: stream,
-            _send_queue: RwLock::new(vec![]),
-            _read_next: 0,
-            _write_next: false,
+            _notifier: notifier,
+            _send_queue: RwLock::new(VecDeque::new()),
+            _reader: Mutex::new(ReadBuffer::new()),
+            _writing: Mutex::new(VecDeque::new()),
+            _pending_bytes: AtomicUsize::new(0),
+            _pending_messages: AtomicUsize::new(0),
+            _watermarks: watermarks,
+            _backpressured: AtomicBool::new(false),
+            _close_requested: AtomicBool::new(false),
+            _paused: AtomicBool::new(false),
+            _resumed: AtomicBool::new(false),
+            _closing: AtomicBool::new(false),
+            _close_started: AtomicBool::new(false),
+            _write_shutdown: AtomicBool::new(false),
+            _read_eof: AtomicBool::new(false),
+            _session: Mutex::new(T::Session::default()),
+            _codec: codec,
+            _framing: framing,
         }
     }
 
     pub fn get_token(&self) -> Token {
-        self._token
+        self._id.token()
+    }
+
+    //pass it to Context::send_message_to_client
+    //to message this client from anywhere
+    pub fn get_id(&self) -> ConnectionId {
+        self._id
     }
 
     //result means, if read success, if fail, should cloase this
-    //option means, got data?
-    pub fn on_read(&mut self) -> io::Result<Option<Vec<u8>>> {
-        let len_result = self.read_message_len();
-        if let Ok(len) = len_result {
-            if len <= 0 {
-                //ewouldblock was returned.
-                return Ok(None);
-            } else {
-                let mut vec: Vec<u8> = Vec::with_capacity(len);
-                unsafe {
-                    vec.set_len(len);
+    //option means, got message?
+    //a whole frame is decoded by the codec, a decode error
+    //is a Codec error
+    pub(crate) fn on_read(&self) -> Result<Option<Inbound<serialize::Item<T>>>> {
+        let mut reader = self._reader.lock().unwrap();
+        loop {
+            match reader.next_frame(&self._framing)? {
+                Some(Inbound::Message((start, len))) => {
+                    let decode_result = self._codec.decode(&reader.buf[start..start + len]);
+                    reader.shrink();
+                    let item = decode_result
+                        .map_err(|e| Error::Codec(e.into()))?;
+                    return Ok(Some(Inbound::Message(item)));
                 }
-                let read_result = self._stream.read(&mut vec);
-                match read_result {
-                    Err(e) => {
-                        if e.kind() == ErrorKind::WouldBlock {
-                            //cache the len. to continue read in next round
-                            self._read_next = len;
-                            return Ok(None);
-                        } else {
-                            println!("read error happend {:?}", e);
-                            return Err(e);
-                        }
+                Some(Inbound::Oversized(len)) => return Ok(Some(Inbound::Oversized(len))),
+                None => {
+                    //nothing whole in the buffer, read more
+                    if !reader.fill(&self._stream)? {
+                        return Ok(None);
                     }
-                    Ok(n) => {
-                        if n < len {
-                            println!("error message number");
-                            return Err(Error::new(ErrorKind::InvalidData, "error message number"));
-                        }
-                        //reset the next buffer
-                        self._read_next = 0;
-                        return Ok(Some(vec));
-                    }
-                } //end match?
+                }
             }
-        } else {
-            return Err(Error::new(ErrorKind::WriteZero, "msg len write failed"));
         }
     }
 
     /*
-    * message =|message len| message buffer| 
-    */
-    fn read_message_len(&mut self) -> io::Result<usize> {
-        if self._read_next > 0 {
-            //_read_next,  means that not all of the data has been recevied
-            //in previous around, so just make it whole completed
-            return Ok(self._read_next);
-        }
-        let mut buf = [0u8; 8];
-        let bytes = match self._stream.read(&mut buf) {
-            Ok(n) => n,
-            Err(e) => {
-                if e.kind() == ErrorKind::WouldBlock {
-                    //0, means current read was blocked.
-                    //it's not a error case,
-                    //should not close socket.
-                    return Ok(0);
-                } else {
-                    return Err(e);
-                }
+     * write as much as the socket takes, until wouldblock
+     * or the queue is empty. the headers and bodies of many
+     * queued frames go out in one writev, a frame which is
+     * partially sent is resumed at its offset in next round
+     */
+    pub(crate) fn on_write(&self) -> Result<()> {
+        let mut writing = self._writing.lock().unwrap();
+        loop {
+            self.take_send_queue(&mut writing)?;
+            if writing.is_empty() {
+                //println!("all message has bee sended");
+                return Ok(());
             }
-        };
-
-        if bytes < 8 {
-            //the message len is u64, so it's 8 bytes long
-            return Err(Error::new(ErrorKind::InvalidData, "Invalid message length"));
-        }
-        let msg_len = BigEndian::read_u64(buf.as_ref());
-        Ok(msg_len as usize)
-    }
-
-    pub fn on_write(&mut self) -> io::Result<()> {
-        let msg_op = {
-            //due to this send queue maybe accessed by multi threads
-            let mut queue = self._send_queue.write().unwrap();
-            queue.pop()
-        };
 
-        if let Some(msg) = msg_op {
-            let write_len_result = self.write_message_len(msg.clone());
-            match write_len_result {
-                Ok(b) => {
-                    if !b {
-                        //save the error message to queue, so that this message
-                        //can be handled next time.
-                        //it's should be ewould block
-                        let mut queue = self._send_queue.write().unwrap();
-                        queue.push(msg);
-                        return Ok(());
+            let write_result = {
+                let mut iovecs: Vec<&IoVec> = Vec::with_capacity(MAX_IOVECS);
+                for state in writing.iter() {
+                    if iovecs.len() + 2 > MAX_IOVECS {
+                        break;
+                    }
+                    let (header, body) = state.remaining();
+                    //an empty slice is not a valid iovec
+                    if !header.is_empty() {
+                        iovecs.push(header.into());
+                    }
+                    if !body.is_empty() {
+                        iovecs.push(body.into());
                     }
                 }
-                Err(e) => {
-                    //error happend
-                    println!("write len failed: {:?}", e);
-                    //in this kind of situation,
-                    //may be close the connection and re-connect
-                    //is a better choice
-                    return Err(e);
-                }
-            } //end write len
+                self._stream.write_bufs(&iovecs)
+            };
 
-            let write_result = self._stream.write(&*msg);
             match write_result {
-                Ok(_) => {
-                    //done , reset the flag, and 
-                    self._write_next = false;
-                    self._stream.flush()?;
-                    return Ok(());
+                Ok(0) => {
+                    return Err(Error::Io(ErrorKind::WriteZero.into()));
+                }
+                Ok(n) => {
+                    let (bytes, frames) = consume_written(&mut writing, n);
+                    self._pending_bytes.fetch_sub(bytes, Ordering::SeqCst);
+                    self._pending_messages.fetch_sub(frames, Ordering::SeqCst);
                 }
                 Err(e) => {
-                    //error happened
                     if e.kind() == ErrorKind::WouldBlock {
-                        //message len has been sent, only send
-                        //message body next time.
-                        self._write_next = true;
-                        let mut queue = self._send_queue.write().unwrap();
-                        queue.push(msg);
-                        println!("on write , would block");
+                        //wouldblock, the rest is handled
+                        //in next round
+                        trace!("on write , would block");
                         return Ok(());
+                    } else if e.kind() != ErrorKind::Interrupted {
+                        //in this kind of situation,
+                        //may be close the connection and re-connect
+                        //is a better choice
+                        return Err(Error::Io(e));
                     }
                 }
             }
-        } else {
-            //println!("all message has bee sended");
         }
+    }
 
+    //move the queued frames to the writing list, with their headers
+    fn take_send_queue(&self, writing: &mut VecDeque<WriteState>) -> Result<()> {
+        //due to this send queue maybe accessed by multi threads
+        let mut queue = self._send_queue.write().unwrap();
+        while let Some(msg) = queue.pop_front() {
+            writing.push_back(WriteState::new(msg, &self._framing)?);
+        }
         Ok(())
     }
 
-    fn write_message_len(&mut self, msg: Arc<Vec<u8>>) -> io::Result<bool> {
-        //means that, the remained message
-        //must be sent in next round
-        if self._write_next {
+    //the unsent bytes and frames
+    pub fn pending(&self) -> (usize, usize) {
+        (self._pending_bytes.load(Ordering::SeqCst),
+         self._pending_messages.load(Ordering::SeqCst))
+    }
+
+    //above a high watermark, the peer doesn't read fast enough
+    pub fn is_congested(&self) -> bool {
+        let (bytes, messages) = self.pending();
+        bytes > self._watermarks._high_bytes || messages > self._watermarks._high_messages
+    }
+
+    //below both low watermarks
+    pub fn is_drained(&self) -> bool {
+        let (bytes, messages) = self.pending();
+        bytes <= self._watermarks._low_bytes && messages <= self._watermarks._low_messages
+    }
+
+    pub fn is_backpressured(&self) -> bool {
+        self._backpressured.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_backpressured(&self, backpressured: bool) {
+        self._backpressured.store(backpressured, Ordering::SeqCst);
+    }
+
+    //the owner worker closes it after the current callback,
+    //the unsent messages are dropped
+    pub fn request_close(&self) {
+        if !self._close_requested.swap(true, Ordering::SeqCst) {
+            self._notifier.notify(self._id.token());
+        }
+    }
+
+    pub fn is_close_requested(&self) -> bool {
+        self._close_requested.load(Ordering::SeqCst)
+    }
+
+    pub fn pause_reading(&self) {
+        self._paused.store(true, Ordering::SeqCst);
+    }
+
+    //the owner worker reads it again, a client not paused is untouched
+    pub fn resume_reading(&self) {
+        if self._paused.swap(false, Ordering::SeqCst) {
+            self._resumed.store(true, Ordering::SeqCst);
+            self._notifier.notify(self._id.token());
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self._paused.load(Ordering::SeqCst)
+    }
+
+    //true once after resume_reading
+    pub(crate) fn take_resumed(&self) -> bool {
+        self._resumed.swap(false, Ordering::SeqCst)
+    }
+
+    /*
+     * close it without losing a reply. the owner worker stops
+     * handling its messages, writes everything queued so far,
+     * shuts the write half down, then waits for the peer's fin.
+     * the flush and the wait take at most the server's linger
+     * timeout together, then the slot is freed anyway.
+     * nothing can be sent to it from now on.
+     * it can be called from any thread
+     */
+    pub fn close_gracefully(&self) {
+        self.start_close();
+    }
+
+    //close_gracefully, false if it's closing already
+    pub(crate) fn start_close(&self) -> bool {
+        if self._closing.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        self._close_started.store(true, Ordering::SeqCst);
+        self._notifier.notify(self._id.token());
+        true
+    }
+
+    //true once after close_gracefully, the linger timeout starts
+    pub(crate) fn take_close_started(&self) -> bool {
+        self._close_started.swap(false, Ordering::SeqCst)
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self._closing.load(Ordering::SeqCst)
+    }
+
+    //send the fin, the queue must be flushed already.
+    //the queue lock keeps a racing send_frame out, it either
+    //queued before, then false is returned, or sees closing
+    pub(crate) fn shutdown_write(&self) -> Result<bool> {
+        let queue = self._send_queue.read().unwrap();
+        if !queue.is_empty() {
             return Ok(false);
         }
+        self._write_shutdown.store(true, Ordering::SeqCst);
+        self._stream.shutdown(Shutdown::Write)?;
+        Ok(true)
+    }
+
+    pub(crate) fn is_write_shutdown(&self) -> bool {
+        self._write_shutdown.load(Ordering::SeqCst)
+    }
 
-        let mut len_buf: [u8; 8] = [0u8; 8]; //a u64 buf
-        //println!("buf.len {}", len_buf.len());
-
-        //big endian, network order
-        BigEndian::write_u64(len_buf.as_mut(), msg.len() as u64);
-        let write_result = self._stream.write(&len_buf);
-        match write_result {
-            Err(e) => {
-                if e.kind() == ErrorKind::WouldBlock {
-                    //wouldblock, should be OK with false result
-                    //to let this message handled in next round
-                    return Ok(false);
-                } else {
-                    return Err(e);
+    //a closing connection still reads, to see the peer's fin.
+    //whatever comes before it is dropped. true means the fin came
+    pub(crate) fn discard_read(&self) -> Result<bool> {
+        let mut reader = self._reader.lock().unwrap();
+        loop {
+            reader.discard();
+            match reader.fill(&self._stream) {
+                Ok(true) => {}
+                Ok(false) => return Ok(self.is_read_eof()),
+                Err(Error::Io(ref e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                    self._read_eof.store(true, Ordering::SeqCst);
+                    return Ok(true);
                 }
-            }
-            Ok(_) => {
-                //means, write success
-                return Ok(true);
+                Err(e) => return Err(e),
             }
         }
     }
 
-    pub fn register(&self, poll: &mut poll::Poller) -> io::Result<()> {
-        poll.register_both(&self._stream, self._token)
+    pub(crate) fn is_read_eof(&self) -> bool {
+        self._read_eof.load(Ordering::SeqCst)
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        Ok(self._stream.peer_addr()?)
+    }
+
+    //the session is locked until the guard is dropped,
+    //don't hold it while calling into the context
+    pub fn session(&self) -> MutexGuard<'_, T::Session> {
+        self._session.lock().unwrap()
+    }
+
+    pub(crate) fn register(&self, poll: &mut poll::Poller) -> io::Result<()> {
+        poll.register_both(&self._stream, self._id.token())
     }
 
     //this message should be public to handler
     //it's multithread.
-    pub fn send_message(&self, msg: Arc<Vec<u8>>) {
-        let mut queue = self._send_queue.write().unwrap();
-        queue.push(msg);
+    //the message is encoded by the codec and queued,
+    //an encode error is a Codec error
+    pub fn send_message(&self, msg: serialize::Item<T>) -> Result<()> {
+        let frame = self._codec.encode(msg)
+            .map_err(|e| Error::Codec(e.into()))?;
+        self.send_frame(Arc::new(frame))
+    }
+
+    //queue an encoded frame body, one frame can be shared
+    //by many clients without copying.
+    //frames go out in exactly the order they are queued,
+    //a frame is never interleaved with, or overtaken by, a later one.
+    //calls from different threads are ordered by the queue lock.
+    //a frame the framing can't express is an OversizedFrame error,
+    //a gracefully closing client takes nothing, it's a Closing error
+    pub fn send_frame(&self, msg: Arc<Vec<u8>>) -> Result<()> {
+        //check it here, so it fails the sender, not the connection
+        let mut header = [0u8; MAX_HEADER_LEN];
+        let header_len = self._framing.encode_header(msg.len(), &mut header)?;
+
+        let was_empty = {
+            let mut queue = self._send_queue.write().unwrap();
+            //under the lock, see shutdown_write
+            if self.is_closing() {
+                return Err(Error::Closing(self._id));
+            }
+
+            //counted before it's visible to the worker,
+            //so it's never uncounted first
+            self._pending_bytes.fetch_add(header_len + msg.len(), Ordering::SeqCst);
+            self._pending_messages.fetch_add(1, Ordering::SeqCst);
+            queue.push_back(msg);
+            queue.len() == 1
+        };
+
+        //a non empty queue is already waiting for a writable edge
+        //or a notification, only the first message needs to wake
+        //the worker up, the socket may have been writable for long
+        if was_empty {
+            self._notifier.notify(self._id.token());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{ByteOrder, BigEndian};
+    use framing::{LengthField, Endian};
+    use std::io::Write;
+    use std::net;
+    use std::thread;
+
+    //a frame of the default framing
+    fn frame(body: &[u8]) -> Vec<u8> {
+        let mut f = vec![0u8; 8];
+        BigEndian::write_u64(&mut f, body.len() as u64);
+        f.extend_from_slice(body);
+        f
+    }
+
+    //as if the bytes were read from the socket
+    fn push(reader: &mut ReadBuffer, bytes: &[u8]) {
+        let wanted = reader.end - reader.start + bytes.len();
+        reader.reserve(wanted);
+        reader.buf[reader.end..reader.end + bytes.len()].copy_from_slice(bytes);
+        reader.end += bytes.len();
+    }
+
+    //every whole message in the buffer
+    fn messages(reader: &mut ReadBuffer, framing: &LengthDelimited) -> Vec<Vec<u8>> {
+        let mut got = vec![];
+        while let Some(Inbound::Message((start, len))) = reader.next_frame(framing).unwrap() {
+            got.push(reader.buf[start..start + len].to_vec());
+        }
+        got
+    }
+
+    //a connected pair, the server end is the nonblocking one
+    fn pair() -> (net::TcpStream, TcpStream) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        (peer, TcpStream::from_stream(stream).unwrap())
+    }
+
+    //read until some bytes come
+    fn fill_some(reader: &mut ReadBuffer, stream: &TcpStream) -> Result<()> {
+        while !reader.fill(stream)? {
+            thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn one_byte_at_a_time() {
+        let framing = LengthDelimited::default();
+        let mut reader = ReadBuffer::new();
+        let bytes = [frame(b"hello"), frame(b""), frame(b"world")].concat();
+
+        let mut got = vec![];
+        for b in bytes.iter() {
+            push(&mut reader, &[*b]);
+            got.extend(messages(&mut reader, &framing));
+        }
+        assert_eq!(got, vec![b"hello".to_vec(), vec![], b"world".to_vec()]);
+        assert_eq!(reader.start, reader.end);
+    }
+
+    #[test]
+    fn varint_one_byte_at_a_time() {
+        let framing = LengthDelimited::new(LengthField::Varint, Endian::Big);
+        let body = vec![9u8; 300];
+        let mut header = [0u8; MAX_HEADER_LEN];
+        let header_len = framing.encode_header(body.len(), &mut header).unwrap();
+        let bytes = [&header[..header_len], &body[..]].concat();
+
+        let mut reader = ReadBuffer::new();
+        let mut got = vec![];
+        for b in bytes.iter() {
+            push(&mut reader, &[*b]);
+            got.extend(messages(&mut reader, &framing));
+        }
+        assert_eq!(got, vec![body]);
+    }
+
+    #[test]
+    fn fill_across_wouldblock() {
+        let (mut peer, stream) = pair();
+        let framing = LengthDelimited::default();
+        let mut reader = ReadBuffer::new();
+        assert!(!reader.fill(&stream).unwrap());
+
+        let mut got = vec![];
+        for b in frame(b"fragmented").iter() {
+            peer.write_all(&[*b]).unwrap();
+            fill_some(&mut reader, &stream).unwrap();
+            got.extend(messages(&mut reader, &framing));
+        }
+        assert_eq!(got, vec![b"fragmented".to_vec()]);
+    }
+
+    #[test]
+    fn eof_between_messages() {
+        let (mut peer, stream) = pair();
+        peer.write_all(&frame(b"whole")).unwrap();
+        drop(peer);
+
+        let framing = LengthDelimited::default();
+        let mut reader = ReadBuffer::new();
+        let mut got = vec![];
+        let e = loop {
+            match fill_some(&mut reader, &stream) {
+                Ok(()) => got.extend(messages(&mut reader, &framing)),
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(got, vec![b"whole".to_vec()]);
+        match e {
+            Error::Io(ref e) if e.kind() == ErrorKind::UnexpectedEof => {}
+            e => panic!("{:?}", e),
+        }
+    }
+
+    #[test]
+    fn eof_in_the_middle_of_a_message() {
+        let (mut peer, stream) = pair();
+        peer.write_all(&frame(b"cut")[..9]).unwrap();
+        drop(peer);
+
+        let framing = LengthDelimited::default();
+        let mut reader = ReadBuffer::new();
+        let e = loop {
+            match fill_some(&mut reader, &stream) {
+                Ok(()) => assert!(messages(&mut reader, &framing).is_empty()),
+                Err(e) => break e,
+            }
+        };
+        match e {
+            Error::Framing(_) => {}
+            e => panic!("{:?}", e),
+        }
+    }
+
+    #[test]
+    fn many_frames_in_one_buffer() {
+        let framing = LengthDelimited::default();
+        let mut reader = ReadBuffer::new();
+        let bodies: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i; i as usize]).collect();
+        let bytes: Vec<u8> = bodies.iter().flat_map(|b| frame(b)).collect();
+
+        //the last one is cut, it's handed out once the rest comes
+        push(&mut reader, &bytes[..bytes.len() - 10]);
+        let mut got = messages(&mut reader, &framing);
+        assert_eq!(got.len(), 99);
+        push(&mut reader, &bytes[bytes.len() - 10..]);
+        got.extend(messages(&mut reader, &framing));
+        assert_eq!(got, bodies);
+    }
+
+    #[test]
+    fn oversized_skipped_across_reads() {
+        let framing = LengthDelimited::default().with_max_frame_len(16);
+        let mut reader = ReadBuffer::new();
+        let big = frame(&[1u8; 100]);
+
+        push(&mut reader, &big[..38]);
+        match reader.next_frame(&framing).unwrap() {
+            Some(Inbound::Oversized(100)) => {}
+            _ => panic!("not oversized"),
+        }
+        assert!(reader.next_frame(&framing).unwrap().is_none());
+        push(&mut reader, &big[38..88]);
+        assert!(reader.next_frame(&framing).unwrap().is_none());
+
+        //the rest of it and a whole one in the same read
+        let next = [&big[88..], &frame(b"next")[..]].concat();
+        push(&mut reader, &next);
+        assert_eq!(messages(&mut reader, &framing), vec![b"next".to_vec()]);
+        assert_eq!(reader.skip, 0);
+    }
+
+    #[test]
+    fn oversized_past_usize() {
+        let framing = LengthDelimited::default();
+        let mut reader = ReadBuffer::new();
+        push(&mut reader, &[0xff; 8]);
+        match reader.next_frame(&framing).unwrap() {
+            Some(Inbound::Oversized(len)) => assert_eq!(len as u64, cmp::min(u64::MAX, usize::MAX as u64)),
+            _ => panic!("not oversized"),
+        }
+        assert_eq!(reader.skip, u64::MAX);
+    }
+
+    #[test]
+    fn grows_for_a_big_frame_then_shrinks() {
+        let framing = LengthDelimited::default();
+        let mut reader = ReadBuffer::new();
+        let body = vec![3u8; READ_BUF_SIZE * 2];
+        push(&mut reader, &frame(&body));
+        assert_eq!(messages(&mut reader, &framing), vec![body]);
+        reader.shrink();
+        assert_eq!(reader.buf.len(), READ_BUF_SIZE);
+    }
+
+    fn writing(bodies: &[&[u8]]) -> VecDeque<WriteState> {
+        let framing = LengthDelimited::default();
+        bodies.iter()
+            .map(|b| WriteState::new(Arc::new(b.to_vec()), &framing).unwrap())
+            .collect()
+    }
+
+    //the unsent bytes of the first frame
+    fn remaining(writing: &VecDeque<WriteState>) -> Vec<u8> {
+        let (header, body) = writing[0].remaining();
+        [header, body].concat()
+    }
+
+    #[test]
+    fn partial_writes_of_one_frame() {
+        let mut w = writing(&[b"body"]);
+        let all = frame(b"body");
+
+        //partway into the header
+        assert_eq!(consume_written(&mut w, 3), (0, 0));
+        assert_eq!(remaining(&w), &all[3..]);
+        //up to the end of the header
+        assert_eq!(consume_written(&mut w, 5), (0, 0));
+        assert_eq!(w[0].remaining(), (&[][..], &b"body"[..]));
+        //partway into the body
+        assert_eq!(consume_written(&mut w, 1), (0, 0));
+        assert_eq!(remaining(&w), b"ody");
+        //the rest of it
+        assert_eq!(consume_written(&mut w, 3), (12, 1));
+        assert!(w.is_empty());
+    }
+
+    #[test]
+    fn nothing_written() {
+        let mut w = writing(&[b"body"]);
+        assert_eq!(consume_written(&mut w, 0), (0, 0));
+        assert_eq!(remaining(&w), frame(b"body"));
+    }
+
+    #[test]
+    fn one_write_across_frames() {
+        let mut w = writing(&[b"a", b"bb", b"ccc", b""]);
+
+        //the first one, and partway into the header of the second
+        assert_eq!(consume_written(&mut w, 9 + 4), (9, 1));
+        assert_eq!(w.len(), 3);
+        assert_eq!(remaining(&w), &frame(b"bb")[4..]);
+
+        //the rest of the second, and the third up to its body
+        assert_eq!(consume_written(&mut w, 6 + 8), (10, 1));
+        assert_eq!(remaining(&w), b"ccc");
+
+        //the third, and the empty one ending exactly at the end
+        assert_eq!(consume_written(&mut w, 3 + 8), (11 + 8, 2));
+        assert!(w.is_empty());
+    }
+
+    #[test]
+    fn one_write_ending_at_a_frame_boundary() {
+        let mut w = writing(&[b"one", b"two", b"three"]);
+        assert_eq!(consume_written(&mut w, 11 + 11), (22, 2));
+        assert_eq!(remaining(&w), frame(b"three"));
+        //more than queued, the extra is ignored
+        assert_eq!(consume_written(&mut w, 100), (13, 1));
+        assert!(w.is_empty());
+    }
+
+    #[test]
+    fn id_round_trip() {
+        let id = ConnectionId::new(2, 5, 7);
+        assert_eq!((id.worker(), id.slot(), id.generation()), (2, 5, 7));
+        assert_eq!(slot_of(id.token()), 5);
+        assert_eq!(ConnectionId::from_token(2, id.token()), id);
+    }
+
+    //the ids of the clients before in the same slot never match
+    #[test]
+    fn stale_generation() {
+        let id = ConnectionId::new(0, 3, 0);
+        let next = ConnectionId::new(0, 3, ConnectionId::next_generation(0));
+        assert!(id != next);
+        assert_eq!(id.slot(), next.slot());
+    }
+
+    #[test]
+    fn generation_wraps() {
+        let last = GENERATION_MASK;
+        assert_eq!(ConnectionId::next_generation(last), 0);
+
+        //the generation never reaches the top bit of the server's tokens
+        let id = ConnectionId::new(0, SLOT_MASK, last);
+        assert_eq!((id.slot(), id.generation()), (SLOT_MASK, last));
+        assert!(id.token().0 <= usize::MAX >> 1);
     }
 }
@@ -1,89 +1,373 @@
-
-/*written by kimikan, 2017-7-12*/
-use std::net::SocketAddr;
-use std::sync::{Arc, RwLock};
-use std::io;
-use std::io::{Error, ErrorKind};
-use mio::{Token};
-use mio::net::{TcpListener, TcpStream};
-use slab;
-use connection;
-use serialize;
-
-/* only one tcplistener */
-pub fn bind(addr:&str)->io::Result<TcpListener> {
-    let address = addr.parse::<SocketAddr>();
-    if let Ok(r) = address {
-        return TcpListener::bind(&r);
-    }
-
-    Err(Error::new(ErrorKind::InvalidInput, "Invalid input"))
-}
-
-//#[derive(Clone)]
-pub struct Context<T: serialize::MessageHandler + Sized> {
-    pub _conns: Arc<RwLock<slab::Slab<connection::Connection, Token>>>,
-    //the refcell used betten than raw trait
-    //it can callback the mut function when needed
-    pub _handle: Arc<RwLock<T>>,
-
-    pub _capacity:usize,
-}
-
-impl<T: serialize::MessageHandler+Sized> Clone for Context<T> {
-    fn clone(&self) -> Self {
-        Context {
-            _conns:Arc::new(RwLock::new(slab::Slab::with_capacity(self._capacity))),
-            _handle: self._handle.clone(),
-            _capacity:self._capacity,
-        }
-    }
-}
-
-impl<T: serialize::MessageHandler + Sized> Context<T> {
-    pub fn new(handle:T, max_clients:usize) -> Self {
-        Context {
-            _conns: Arc::new(RwLock::new(slab::Slab::with_capacity(max_clients))),
-            _handle:Arc::new(RwLock::new(handle)),
-            _capacity:max_clients,
-        }
-    }
-
-    pub fn remove_client(&self, token: Token) {
-        let mut clients = self._conns.write().unwrap();
-        clients.remove(token);
-    }
-
-    pub fn available_token(&self, client: TcpStream) -> Option<Token> {
-        let mut conns = self._conns.write().unwrap();
-        let entry_op = conns.vacant_entry();
-        let token = match entry_op {
-            Some(e) => {
-                let connection = connection::Connection::new(client, e.index());
-                e.insert(connection).index()
-            }
-            None => {
-                println!("no empty entry for new clients");
-                return None;
-            }
-        };
-
-        Some(token)
-    }
-
-    #[allow(dead_code)]
-    pub fn send_message_to_client(&self, token: Token, msg: Arc<Vec<u8>>) -> io::Result<()> {
-        let conns = self._conns.read().unwrap();
-        let client_op = conns.get(token);
-        let client = match client_op {
-            Some(expr) => expr,
-            None => {
-                println!("no client got:{:?}", token);
-                return Err(Error::new(ErrorKind::InvalidData, "invlid token"));
-            }
-        };
-
-        client.send_message(msg.clone());
-        Ok(())
-    } //end send?
-}
+
+/*written by kimikan, 2017-7-12*/
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+use mio::net::TcpListener;
+use slab;
+use connection;
+use connection::{Connection, ConnectionId};
+use poll::{Notifier, Timers};
+use serialize;
+use serialize::MessageCodec;
+use error::{Error, Result};
+
+/* only one tcplistener */
+pub fn bind(addr:&str)->Result<TcpListener> {
+    let address = addr.parse::<SocketAddr>()?;
+    Ok(TcpListener::bind(&address)?)
+}
+
+//the connections owned by one worker, indexed by slot
+//every client is shared, so it's used without the lock
+pub(crate) type Clients<T> = Arc<RwLock<slab::Slab<Arc<connection::Connection<T>>, usize>>>;
+
+/*
+ * shared by all of the workers, a clone is just
+ * another handle to the same registry and handler.
+ * every worker puts its own clients into the registry,
+ * so a message can be routed to any client by id
+ */
+pub struct Context<T: serialize::MessageHandler + Sized> {
+    //indexed by worker
+    pub(crate) _workers: Arc<RwLock<Vec<Clients<T>>>>,
+    //the refcell used betten than raw trait
+    //it can callback the mut function when needed
+    pub(crate) _handle: Arc<RwLock<T>>,
+    pub(crate) _codec: Arc<T::Codec>,
+
+    //max clients of every worker
+    pub(crate) _capacity:usize,
+    //the clients of all of the workers, and the max of them,
+    //the max can be changed while running
+    pub(crate) _clients: Arc<AtomicUsize>,
+    pub(crate) _max_clients: Arc<AtomicUsize>,
+
+    //wakes the worker of the same index up
+    pub(crate) _wakers: Arc<RwLock<Vec<Notifier>>>,
+    //set by shutdown, the clients left at it are closed
+    pub(crate) _shutdown: Arc<Mutex<Option<Instant>>>,
+}
+
+impl<T: serialize::MessageHandler+Sized> Clone for Context<T> {
+    fn clone(&self) -> Self {
+        Context {
+            _workers: self._workers.clone(),
+            _handle: self._handle.clone(),
+            _codec: self._codec.clone(),
+            _capacity:self._capacity,
+            _clients: self._clients.clone(),
+            _max_clients: self._max_clients.clone(),
+            _wakers: self._wakers.clone(),
+            _shutdown: self._shutdown.clone(),
+        }
+    }
+}
+
+impl<T: serialize::MessageHandler + Sized> Context<T> {
+    pub fn new(handle:T, codec:T::Codec, max_clients:usize) -> Result<Self> {
+        //the slot must fit into the token
+        if max_clients > connection::MAX_CLIENTS {
+            return Err(Error::InvalidConfig("too many clients per worker"));
+        }
+        Ok(Context {
+            _workers: Arc::new(RwLock::new(vec![])),
+            _handle:Arc::new(RwLock::new(handle)),
+            _codec:Arc::new(codec),
+            _capacity:max_clients,
+            _clients: Arc::new(AtomicUsize::new(0)),
+            _max_clients: Arc::new(AtomicUsize::new(usize::MAX)),
+            _wakers: Arc::new(RwLock::new(vec![])),
+            _shutdown: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    //the max clients of all of the workers together,
+    //the one over it is dropped on accept
+    pub fn with_max_total_clients(self, max: usize) -> Self {
+        self.set_max_total_clients(max);
+        self
+    }
+
+    //the clients connected already stay if it's lowered
+    pub fn set_max_total_clients(&self, max: usize) {
+        self._max_clients.store(max, Ordering::SeqCst);
+    }
+
+    //how many clients are connected to all of the workers
+    pub fn clients(&self) -> usize {
+        self._clients.load(Ordering::SeqCst)
+    }
+
+    //false if the global limit is reached
+    pub(crate) fn add_client(&self) -> bool {
+        let max = self._max_clients.load(Ordering::SeqCst);
+        self._clients
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst,
+                          |n| if n < max { Some(n + 1) } else { None })
+            .is_ok()
+    }
+
+    pub(crate) fn remove_client(&self) {
+        self._clients.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    //a new worker joined, it gets an index and an empty slab
+    pub(crate) fn add_worker(&self, waker: Notifier) -> (usize, Clients<T>) {
+        let clients = Arc::new(RwLock::new(slab::Slab::with_capacity(self._capacity)));
+        let mut workers = self._workers.write().unwrap();
+        workers.push(clients.clone());
+        self._wakers.write().unwrap().push(waker);
+        (workers.len() - 1, clients)
+    }
+
+    /*
+     * stop the server from any thread, every worker stops
+     * accepting and closes its clients gracefully, the replies
+     * queued are sent first. the clients left after the timeout
+     * are closed anyway, then the workers return from run.
+     * calling it again can only bring the deadline forward
+     */
+    pub fn shutdown(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        {
+            let mut shutdown = self._shutdown.lock().unwrap();
+            match *shutdown {
+                Some(d) if d <= deadline => {}
+                _ => *shutdown = Some(deadline),
+            }
+        }
+        for waker in self._wakers.read().unwrap().iter() {
+            waker.wake();
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self._shutdown.lock().unwrap().is_some()
+    }
+
+    pub(crate) fn shutdown_deadline(&self) -> Option<Instant> {
+        *self._shutdown.lock().unwrap()
+    }
+
+    //the client of the id, on any worker.
+    //the slab is locked only to clone it
+    pub fn find_client(&self, id: ConnectionId) -> Result<Arc<Connection<T>>> {
+        let clients = {
+            let workers = self._workers.read().unwrap();
+            match workers.get(id.worker()) {
+                Some(c) => c.clone(),
+                None => {
+                    debug!("no worker got:{:?}", id);
+                    return Err(Error::UnknownConnection(id));
+                }
+            }
+        };
+
+        let conns = clients.read().unwrap();
+        match conns.get(id.slot()) {
+            Some(expr) if expr.get_id() == id => Ok(expr.clone()),
+            Some(_) => {
+                //the client is gone, the slot was taken by a new one
+                debug!("stale client id:{:?}", id);
+                Err(Error::UnknownConnection(id))
+            }
+            None => {
+                debug!("no client got:{:?}", id);
+                Err(Error::UnknownConnection(id))
+            }
+        }
+    }
+
+    //it can be called from any thread, the owner worker
+    //of the client is woken up to flush the message.
+    //same ordering guarantee as Connection::send_message,
+    //the messages to one client are sent fifo
+    pub fn send_message_to_client(&self, id: ConnectionId, msg: serialize::Item<T>) -> Result<()> {
+        self.find_client(id)?.send_message(msg)
+    } //end send?
+
+    //send to every client of every worker, the message is
+    //encoded once and shared. returns how many got it,
+    //a client closing or failed to queue it is skipped
+    pub fn broadcast(&self, msg: serialize::Item<T>) -> Result<usize> {
+        let frame = self._codec.encode(msg)
+            .map_err(|e| Error::Codec(e.into()))?;
+        let frame = Arc::new(frame);
+
+        let mut clients = vec![];
+        for conns in self._workers.read().unwrap().iter() {
+            clients.extend(conns.read().unwrap().iter().cloned());
+        }
+
+        let mut sent = 0;
+        //the closing ones take nothing more
+        for client in clients.into_iter().filter(|c| !c.is_closing()) {
+            match client.send_frame(frame.clone()) {
+                Ok(()) => sent += 1,
+                Err(e) => debug!("broadcast to {:?} failed {:?}", client.get_id(), e),
+            }
+        }
+        Ok(sent)
+    }
+
+    //the owner worker closes the client soon
+    pub fn close_client(&self, id: ConnectionId) -> Result<()> {
+        self.find_client(id)?.request_close();
+        Ok(())
+    }
+
+    //flush, then close, see Connection::close_gracefully
+    pub fn close_client_gracefully(&self, id: ConnectionId) -> Result<()> {
+        self.find_client(id)?.close_gracefully();
+        Ok(())
+    }
+
+    //the client paused by HandlerAction::PauseReading goes on
+    pub fn resume_reading(&self, id: ConnectionId) -> Result<()> {
+        self.find_client(id)?.resume_reading();
+        Ok(())
+    }
+
+    pub fn peer_addr(&self, id: ConnectionId) -> Result<SocketAddr> {
+        self.find_client(id)?.peer_addr()
+    }
+}
+
+/*
+ * what the handler callbacks get, the client of the event,
+ * and the way to the other clients and the worker.
+ * it's only valid during the callback, keep the id
+ * to reach the client later
+ */
+pub struct HandlerContext<'a, T: serialize::MessageHandler + Sized> {
+    _context: &'a Context<T>,
+    _connection: &'a Arc<Connection<T>>,
+    //the timers of the worker running the callback
+    _timers: &'a RefCell<Timers>,
+}
+
+impl<'a, T: serialize::MessageHandler + Sized> HandlerContext<'a, T> {
+    pub(crate) fn new(context: &'a Context<T>, connection: &'a Arc<Connection<T>>,
+               timers: &'a RefCell<Timers>) -> Self {
+        HandlerContext {
+            _context: context,
+            _connection: connection,
+            _timers: timers,
+        }
+    }
+
+    //the client of the event
+    pub fn connection(&self) -> &Connection<T> {
+        self._connection
+    }
+
+    pub fn id(&self) -> ConnectionId {
+        self._connection.get_id()
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        self._connection.peer_addr()
+    }
+
+    //reply to the client of the event
+    pub fn send(&self, msg: serialize::Item<T>) -> Result<()> {
+        self._connection.send_message(msg)
+    }
+
+    pub fn send_to(&self, id: ConnectionId, msg: serialize::Item<T>) -> Result<()> {
+        self._context.send_message_to_client(id, msg)
+    }
+
+    pub fn broadcast(&self, msg: serialize::Item<T>) -> Result<usize> {
+        self._context.broadcast(msg)
+    }
+
+    //close the client of the event once the callback returns,
+    //no more of its messages are handled
+    pub fn close(&self) {
+        self._connection.request_close();
+    }
+
+    //the replies queued so far are sent first
+    pub fn close_gracefully(&self) {
+        self._connection.close_gracefully();
+    }
+
+    pub fn close_client(&self, id: ConnectionId) -> Result<()> {
+        self._context.close_client(id)
+    }
+
+    //the client of the event, paused by HandlerAction::PauseReading,
+    //is read again after the callback
+    pub fn resume_reading(&self) {
+        self._connection.resume_reading();
+    }
+
+    pub fn peer_addr_of(&self, id: ConnectionId) -> Result<SocketAddr> {
+        self._context.peer_addr(id)
+    }
+
+    //on_timer is called with the timer on the client of the event
+    //after the delay, unless it's closed by then
+    pub fn schedule(&self, delay: Duration, timer: usize) {
+        let at = Instant::now() + delay;
+        self._timers.borrow_mut().schedule(at, self._connection.get_token(), timer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net;
+    use mio::net::TcpStream;
+    use framing::LengthDelimited;
+    use connection::Watermarks;
+    use poll::Poller;
+    use serialize::{HandlerAction, RawCodec};
+    use error;
+
+    struct Nop;
+
+    impl serialize::MessageHandler for Nop {
+        type Session = ();
+        type Codec = RawCodec;
+
+        fn on_message_received(&self, _: &HandlerContext<Self>, _: Vec<u8>) -> error::Result<HandlerAction> {
+            Ok(HandlerAction::Continue)
+        }
+    }
+
+    fn stream() -> TcpStream {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _peer = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        TcpStream::from_stream(listener.accept().unwrap().0).unwrap()
+    }
+
+    fn unknown(r: Result<Arc<Connection<Nop>>>) -> bool {
+        matches!(r, Err(Error::UnknownConnection(_)))
+    }
+
+    //a client in slot 0 of worker 0, generation 1
+    #[test]
+    fn stale_ids_are_rejected() {
+        let ctx = Context::new(Nop, RawCodec, 4).unwrap();
+        let poller = Poller::new().unwrap();
+        let (worker, clients) = ctx.add_worker(poller.notifier());
+
+        let id = ConnectionId::new(worker, 0, 1);
+        let connection = Connection::new(stream(), id, poller.notifier(), ctx._codec.clone(),
+                                         LengthDelimited::default(), Watermarks::default());
+        assert_eq!(clients.write().unwrap().insert(Arc::new(connection)).ok(), Some(0));
+
+        assert_eq!(ctx.find_client(id).unwrap().get_id(), id);
+        //the client before it, and the one after it
+        assert!(unknown(ctx.find_client(ConnectionId::new(worker, 0, 0))));
+        assert!(unknown(ctx.find_client(ConnectionId::new(worker, 0, 2))));
+        //an empty slot, and a worker which doesn't exist
+        assert!(unknown(ctx.find_client(ConnectionId::new(worker, 1, 1))));
+        assert!(unknown(ctx.find_client(ConnectionId::new(worker + 1, 0, 1))));
+    }
+}
@@ -0,0 +1,113 @@
+/*written by kimikan, 2017-7-12*/
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::AddrParseError;
+use std::result;
+
+use connection::ConnectionId;
+
+/*
+ * what can go wrong in the server, match on it to tell
+ * the causes apart. it converts to io::Error, the kind
+ * tells roughly the same
+ */
+#[derive(Debug)]
+pub enum Error {
+    //the length prefix is invalid, or the peer
+    //closed in the middle of a message
+    Framing(&'static str),
+    //a message longer than the framing can take
+    OversizedFrame(usize),
+    //the codec failed to decode or encode a message
+    Codec(Box<dyn error::Error + Send + Sync>),
+    //the client is gone, or the id never existed
+    UnknownConnection(ConnectionId),
+    //the client is closing gracefully, it takes no more message
+    Closing(ConnectionId),
+    //no free slot for a new client
+    CapacityExhausted,
+    //the handler failed, the connection is closed
+    Handler(Box<dyn error::Error + Send + Sync>),
+    //the listen address can't be parsed
+    InvalidAddress(AddrParseError),
+    //the settings of the server can't work together
+    InvalidConfig(&'static str),
+    Io(io::Error),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl Error {
+    //wrap any error of the handler
+    pub fn handler<E>(e: E) -> Error
+        where E: Into<Box<dyn error::Error + Send + Sync>>
+    {
+        Error::Handler(e.into())
+    }
+
+    //the io::ErrorKind it converts to
+    pub fn kind(&self) -> io::ErrorKind {
+        match *self {
+            Error::Framing(_) |
+            Error::OversizedFrame(_) |
+            Error::Codec(_) => io::ErrorKind::InvalidData,
+            Error::UnknownConnection(_) => io::ErrorKind::NotFound,
+            Error::Closing(_) => io::ErrorKind::NotConnected,
+            Error::InvalidAddress(_) |
+            Error::InvalidConfig(_) => io::ErrorKind::InvalidInput,
+            Error::CapacityExhausted |
+            Error::Handler(_) => io::ErrorKind::Other,
+            Error::Io(ref e) => e.kind(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Framing(msg) => write!(f, "framing error: {}", msg),
+            Error::OversizedFrame(len) => write!(f, "oversized frame: {} bytes", len),
+            Error::Codec(ref e) => write!(f, "codec error: {}", e),
+            Error::UnknownConnection(id) => write!(f, "unknown connection: {:?}", id),
+            Error::Closing(id) => write!(f, "connection closing: {:?}", id),
+            Error::CapacityExhausted => write!(f, "no free slot for a new client"),
+            Error::Handler(ref e) => write!(f, "handler error: {}", e),
+            Error::InvalidAddress(ref e) => write!(f, "invalid address: {}", e),
+            Error::InvalidConfig(msg) => write!(f, "invalid config: {}", msg),
+            Error::Io(ref e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Codec(ref e) | Error::Handler(ref e) => Some(&**e),
+            Error::InvalidAddress(ref e) => Some(e),
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<AddrParseError> for Error {
+    fn from(e: AddrParseError) -> Error {
+        Error::InvalidAddress(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
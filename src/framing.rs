@@ -0,0 +1,334 @@
+/*written by kimikan, 2017-7-12*/
+use byteorder::{ByteOrder, BigEndian, LittleEndian};
+use std::cmp;
+
+use error::{Error, Result};
+
+//a u64 varint is at most 10 bytes
+pub const MAX_HEADER_LEN: usize = 10;
+//the default max frame len, 8M
+pub const MAX_FRAME_LEN: usize = 8 * 1024 * 1024;
+
+//how the message len is written in the header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LengthField {
+    U16,
+    U32,
+    U64,
+    //unsigned LEB128, 7 bits a byte, low bits first
+    Varint,
+}
+
+//byte order of the fixed width fields, varint ignores it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endian {
+    Big,
+    Little,
+}
+
+/*
+ * message =|message len| message buffer|
+ * the length prefix framing of a listener,
+ * the default one is 8 bytes big endian, the message len
+ * is the message buffer len.
+ * for the peers counting something else in the len:
+ *  body len = message len + adjustment - (header len, if included)
+ * a body longer than the max frame len is never buffered
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LengthDelimited {
+    _field: LengthField,
+    _endian: Endian,
+    _adjustment: i64,
+    _includes_header: bool,
+    _max_frame_len: usize,
+}
+
+impl Default for LengthDelimited {
+    fn default() -> LengthDelimited {
+        LengthDelimited::new(LengthField::U64, Endian::Big)
+    }
+}
+
+impl LengthDelimited {
+    pub fn new(field: LengthField, endian: Endian) -> LengthDelimited {
+        LengthDelimited {
+            _field: field,
+            _endian: endian,
+            _adjustment: 0,
+            _includes_header: false,
+            _max_frame_len: MAX_FRAME_LEN,
+        }
+    }
+
+    //added to the message len to get the body len
+    pub fn with_adjustment(mut self, adjustment: i64) -> LengthDelimited {
+        self._adjustment = adjustment;
+        self
+    }
+
+    //the message len counts the header itself
+    pub fn with_header_included(mut self, included: bool) -> LengthDelimited {
+        self._includes_header = included;
+        self
+    }
+
+    //the max body len accepted from the peer
+    pub fn with_max_frame_len(mut self, max: usize) -> LengthDelimited {
+        self._max_frame_len = max;
+        self
+    }
+
+    pub fn max_frame_len(&self) -> usize {
+        self._max_frame_len
+    }
+
+    /*
+     * Some((header len, body len)) if buf starts with a whole header,
+     * None means more bytes are needed. the body len is whatever the
+     * peer sent, it may not even fit usize, check it against the max
+     * frame len before using it
+     */
+    pub fn decode_header(&self, buf: &[u8]) -> Result<Option<(usize, u64)>> {
+        let (header_len, value) = match self._field {
+            LengthField::U16 => {
+                if buf.len() < 2 {
+                    return Ok(None);
+                }
+                (2, match self._endian {
+                    Endian::Big => BigEndian::read_u16(buf) as u64,
+                    Endian::Little => LittleEndian::read_u16(buf) as u64,
+                })
+            }
+            LengthField::U32 => {
+                if buf.len() < 4 {
+                    return Ok(None);
+                }
+                (4, match self._endian {
+                    Endian::Big => BigEndian::read_u32(buf) as u64,
+                    Endian::Little => LittleEndian::read_u32(buf) as u64,
+                })
+            }
+            LengthField::U64 => {
+                if buf.len() < 8 {
+                    return Ok(None);
+                }
+                (8, match self._endian {
+                    Endian::Big => BigEndian::read_u64(buf),
+                    Endian::Little => LittleEndian::read_u64(buf),
+                })
+            }
+            LengthField::Varint => {
+                match Self::read_varint(buf)? {
+                    Some(r) => r,
+                    None => return Ok(None),
+                }
+            }
+        };
+
+        //wide enough for any value and adjustment, a len past
+        //u64 is oversized anyway, so it's clamped
+        let included = if self._includes_header { header_len as i128 } else { 0 };
+        let body_len = value as i128 + self._adjustment as i128 - included;
+        if body_len < 0 {
+            return Err(Error::Framing("invalid message length"));
+        }
+        Ok(Some((header_len, cmp::min(body_len, u64::MAX as i128) as u64)))
+    }
+
+    //writes the header of a body_len message, returns the header len.
+    //a body too long for the length field is an OversizedFrame error
+    pub fn encode_header(&self, body_len: usize, buf: &mut [u8; MAX_HEADER_LEN]) -> Result<usize> {
+        let header_len = match self._field {
+            LengthField::U16 => 2,
+            LengthField::U32 => 4,
+            LengthField::U64 => 8,
+            LengthField::Varint => {
+                //the varint len depends on the value, which depends
+                //on the varint len if the header is included.
+                //it only grows, so it settles in a few rounds
+                let mut len = 1;
+                loop {
+                    let l = Self::varint_len(self.header_value(body_len, len)?);
+                    if l == len {
+                        break len;
+                    }
+                    len = l;
+                }
+            }
+        };
+
+        let value = self.header_value(body_len, header_len)?;
+        let too_long = match self._field {
+            LengthField::U16 => value > u16::MAX as u64,
+            LengthField::U32 => value > u32::MAX as u64,
+            _ => false,
+        };
+        if too_long {
+            return Err(Error::OversizedFrame(body_len));
+        }
+
+        match (self._field, self._endian) {
+            (LengthField::U16, Endian::Big) => BigEndian::write_u16(buf, value as u16),
+            (LengthField::U16, Endian::Little) => LittleEndian::write_u16(buf, value as u16),
+            (LengthField::U32, Endian::Big) => BigEndian::write_u32(buf, value as u32),
+            (LengthField::U32, Endian::Little) => LittleEndian::write_u32(buf, value as u32),
+            (LengthField::U64, Endian::Big) => BigEndian::write_u64(buf, value),
+            (LengthField::U64, Endian::Little) => LittleEndian::write_u64(buf, value),
+            (LengthField::Varint, _) => Self::write_varint(value, buf),
+        }
+        Ok(header_len)
+    }
+
+    //the message len to put in a header_len bytes header
+    fn header_value(&self, body_len: usize, header_len: usize) -> Result<u64> {
+        let included = if self._includes_header { header_len as i64 } else { 0 };
+        let value = (body_len as i64)
+            .checked_sub(self._adjustment)
+            .and_then(|l| l.checked_add(included));
+        match value {
+            Some(v) if v >= 0 => Ok(v as u64),
+            _ => Err(Error::Framing("message length can't be framed")),
+        }
+    }
+
+    fn read_varint(buf: &[u8]) -> Result<Option<(usize, u64)>> {
+        let mut value: u64 = 0;
+        for (i, b) in buf.iter().enumerate() {
+            if i >= MAX_HEADER_LEN || (i == MAX_HEADER_LEN - 1 && *b > 1) {
+                return Err(Error::Framing("varint message length overflow"));
+            }
+            value |= ((b & 0x7f) as u64) << (7 * i);
+            if b & 0x80 == 0 {
+                return Ok(Some((i + 1, value)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn varint_len(mut value: u64) -> usize {
+        let mut len = 1;
+        while value >= 0x80 {
+            value >>= 7;
+            len += 1;
+        }
+        len
+    }
+
+    fn write_varint(mut value: u64, buf: &mut [u8]) {
+        let mut i = 0;
+        while value >= 0x80 {
+            buf[i] = (value as u8) | 0x80;
+            value >>= 7;
+            i += 1;
+        }
+        buf[i] = value as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIELDS: [LengthField; 4] = [LengthField::U16, LengthField::U32, LengthField::U64, LengthField::Varint];
+    const ENDIANS: [Endian; 2] = [Endian::Big, Endian::Little];
+
+    //every body len a header can carry, encoded then decoded
+    #[test]
+    fn round_trip() {
+        for &field in FIELDS.iter() {
+            for &endian in ENDIANS.iter() {
+                for &adjustment in [-12i64, -2, 0, 3, 10].iter() {
+                    for &included in [false, true].iter() {
+                        let framing = LengthDelimited::new(field, endian)
+                            .with_adjustment(adjustment)
+                            .with_header_included(included);
+                        for &body_len in [0usize, 1, 10, 127, 128, 300, 16383, 16384, 65535, 1 << 20].iter() {
+                            let mut buf = [0u8; MAX_HEADER_LEN];
+                            let header_len = match framing.encode_header(body_len, &mut buf) {
+                                Ok(l) => l,
+                                //a negative or too long message len
+                                Err(Error::Framing(_)) | Err(Error::OversizedFrame(_)) => continue,
+                                Err(e) => panic!("{:?}", e),
+                            };
+
+                            let decoded = framing.decode_header(&buf[..header_len]).unwrap();
+                            assert_eq!(decoded, Some((header_len, body_len as u64)),
+                                       "{:?} {}", framing, body_len);
+                            //a header cut short needs more bytes
+                            assert_eq!(framing.decode_header(&buf[..header_len - 1]).unwrap(), None);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fixed_width_headers() {
+        let mut buf = [0u8; MAX_HEADER_LEN];
+        let framing = LengthDelimited::new(LengthField::U32, Endian::Little);
+        assert_eq!(framing.encode_header(0x0102, &mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], &[0x02, 0x01, 0, 0]);
+
+        let framing = LengthDelimited::new(LengthField::U16, Endian::Big)
+            .with_header_included(true);
+        assert_eq!(framing.encode_header(1, &mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], &[0, 3]);
+    }
+
+    #[test]
+    fn too_long_for_the_field() {
+        let mut buf = [0u8; MAX_HEADER_LEN];
+        let framing = LengthDelimited::new(LengthField::U16, Endian::Big);
+        match framing.encode_header(65536, &mut buf) {
+            Err(Error::OversizedFrame(65536)) => {}
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn negative_len() {
+        let framing = LengthDelimited::new(LengthField::U16, Endian::Big)
+            .with_header_included(true);
+        //a len of 1 can't include the 2 bytes header
+        match framing.decode_header(&[0, 1]) {
+            Err(Error::Framing(_)) => {}
+            r => panic!("{:?}", r),
+        }
+    }
+
+    //the len is the peer's, it's never trusted to fit anything
+    #[test]
+    fn huge_len() {
+        let framing = LengthDelimited::default();
+        assert_eq!(framing.decode_header(&[0xff; 8]).unwrap(), Some((8, u64::MAX)));
+
+        let framing = framing.with_adjustment(100);
+        assert_eq!(framing.decode_header(&[0xff; 8]).unwrap(), Some((8, u64::MAX)));
+
+        let framing = LengthDelimited::default().with_adjustment(-1);
+        assert_eq!(framing.decode_header(&[0x80, 0, 0, 0, 0, 0, 0, 0]).unwrap(),
+                   Some((8, (1u64 << 63) - 1)));
+    }
+
+    #[test]
+    fn varint_overflow() {
+        let framing = LengthDelimited::new(LengthField::Varint, Endian::Big);
+
+        //the tenth byte has room for one bit only
+        let mut max = [0xffu8; 10];
+        max[9] = 0x01;
+        assert_eq!(framing.decode_header(&max).unwrap(), Some((10, u64::MAX)));
+
+        let mut over = max;
+        over[9] = 0x02;
+        assert!(framing.decode_header(&over).is_err());
+
+        //an eleventh byte is never valid
+        assert!(framing.decode_header(&[0xff; 11]).is_err());
+        //nine bytes still going need more
+        assert_eq!(framing.decode_header(&[0xff; 9]).unwrap(), None);
+    }
+}
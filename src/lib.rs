@@ -0,0 +1,31 @@
+/*written by kimikan, 2017-7-12*/
+extern crate mio;
+extern crate byteorder;
+extern crate slab;
+extern crate iovec;
+#[macro_use]
+extern crate log;
+
+mod server;
+mod connection;
+mod poll;
+mod context;
+mod serialize;
+mod framing;
+mod error;
+mod builder;
+
+/*
+ * the public api, everything else is an implementation detail.
+ * a server is a Context shared by the workers, every worker is
+ * a Server running on its own thread, the application plugs
+ * in a MessageHandler and the codec of its messages.
+ * ServerBuilder puts them together
+ */
+pub use server::{Server, ServerStats, OversizePolicy, LINGER_TIMEOUT, EVENTS_CAPACITY};
+pub use builder::{ServerBuilder, ServerHandle, DEFAULT_ADDR, DEFAULT_WORKERS, DEFAULT_MAX_CLIENTS_PER_WORKER};
+pub use context::{bind, Context, HandlerContext};
+pub use connection::{Connection, ConnectionId, Watermarks, SocketOptions, MAX_CLIENTS};
+pub use serialize::{MessageCodec, RawCodec, MessageHandler, HandlerAction, DisconnectReason, Item};
+pub use framing::{LengthDelimited, LengthField, Endian, MAX_FRAME_LEN};
+pub use error::{Error, Result};
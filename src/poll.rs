@@ -1,55 +1,151 @@
 
 /*written by kimikan, 2017-7-12*/
-use mio::{Events, Poll, Token, Ready, PollOpt, Evented};
+use mio::{Events, Poll, Token, Ready, PollOpt, Evented, Registration, SetReadiness};
+use std::sync::{Arc, Mutex};
+use std::collections::BinaryHeap;
+use std::cmp::Reverse;
+use std::mem;
+use std::time::{Duration, Instant};
 use std::io;
 
+/*
+ * wakes the poller up from any thread,
+ * and tells it which tokens have something to do
+ */
+#[derive(Clone)]
+pub struct Notifier {
+    _readiness: SetReadiness,
+    _pending: Arc<Mutex<Vec<Token>>>,
+}
+
+impl Notifier {
+    pub fn notify(&self, token: Token) {
+        self._pending.lock().unwrap().push(token);
+        if let Err(e) = self._readiness.set_readiness(Ready::readable()) {
+            warn!("notify failed: {:?}", e);
+        }
+    }
+
+    //just wake the poller up, no token has anything to do
+    pub fn wake(&self) {
+        if let Err(e) = self._readiness.set_readiness(Ready::readable()) {
+            warn!("wake failed: {:?}", e);
+        }
+    }
+
+    //reset the readiness before taking the tokens,
+    //so a notify racing with this is never lost
+    pub fn take_pending(&self) -> Vec<Token> {
+        if let Err(e) = self._readiness.set_readiness(Ready::empty()) {
+            warn!("reset readiness failed: {:?}", e);
+        }
+        let mut pending = self._pending.lock().unwrap();
+        mem::take(&mut *pending)
+    }
+}
+
+/*
+ * the timers scheduled by the handlers of one worker,
+ * a timer is the token of its client and a number chosen
+ * by the handler, the earliest one bounds the poll timeout
+ */
+#[derive(Default)]
+pub struct Timers {
+    _heap: BinaryHeap<Reverse<(Instant, Token, usize)>>,
+}
+
+impl Timers {
+    pub fn schedule(&mut self, at: Instant, token: Token, timer: usize) {
+        self._heap.push(Reverse((at, token, timer)));
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self._heap.peek().map(|t| (t.0).0)
+    }
+
+    //remove the timers due at now, earliest first
+    pub fn expired(&mut self, now: Instant) -> Vec<(Token, usize)> {
+        let mut expired = vec![];
+        while let Some(t) = self._heap.peek() {
+            if (t.0).0 > now {
+                break;
+            }
+            let Reverse((_, token, timer)) = self._heap.pop().unwrap();
+            expired.push((token, timer));
+        }
+        expired
+    }
+}
+
 /* just a wrapper */
 pub struct Poller {
     pub _poll: Poll,
+
+    //user space event source, fired by the notifier
+    _registration: Registration,
+    _notifier: Notifier,
 }
 
 impl Poller {
     pub fn new() -> io::Result<Poller> {
         let poll = Poll::new()?;
+        let (registration, readiness) = Registration::new2();
+        let notifier = Notifier {
+            _readiness: readiness,
+            _pending: Arc::new(Mutex::new(vec![])),
+        };
+
+        Ok(Poller {
+            _poll: poll,
+            _registration: registration,
+            _notifier: notifier,
+        })
+    }
+
+    pub fn notifier(&self) -> Notifier {
+        self._notifier.clone()
+    }
 
-        return Ok(Poller { _poll: poll });
+    pub fn register_notifier(&self, token: Token) -> io::Result<()> {
+        self.register_read(&self._registration, token)
     }
 
-    pub fn poll_once(&self, events: &mut Events) -> io::Result<usize> {
+    //none timeout means wait until some event comes
+    pub fn poll_once(&self, events: &mut Events, timeout: Option<Duration>) -> io::Result<usize> {
         //println!("poll: {:?} {:?}", self._poll, events);
-        self._poll.poll(events, None)
+        self._poll.poll(events, timeout)
     }
 
-    pub fn deregister<E:?Sized>(&self, handle:&E)->io::Result<()>
-        where E:Evented {
+    pub fn deregister<E>(&self, handle:&E)->io::Result<()>
+        where E:Evented + ?Sized {
             self._poll.deregister(handle)
     }
 
-    pub fn register_read<E: ?Sized>(&self, handle: &E, token: Token) -> io::Result<()>
-        where E: Evented
+    pub fn register_read<E>(&self, handle: &E, token: Token) -> io::Result<()>
+        where E: Evented + ?Sized
     {
         self.register(handle, token, Ready::readable(), PollOpt::edge())
     }
 
-    pub fn register_both<E: ?Sized>(&self, handle: &E, token: Token) -> io::Result<()>
-        where E: Evented
+    pub fn register_both<E>(&self, handle: &E, token: Token) -> io::Result<()>
+        where E: Evented + ?Sized
     {
         let mut ready = Ready::readable();
         ready.insert(Ready::writable());
         self.register(handle, token, ready, PollOpt::edge())
     }
 
-    pub fn register<E: ?Sized>(&self,
+    pub fn register<E>(&self,
                                handle: &E,
                                token: Token,
                                interest: Ready,
                                opts: PollOpt)
                                -> io::Result<()>
-        where E: Evented
+        where E: Evented + ?Sized
     {
-        let result = self._poll.register(handle, token, interest, opts)?;
-        println!("regist: {:?}, {:?}, {:?}", token, interest, opts);
-        Ok(result)
+        self._poll.register(handle, token, interest, opts)?;
+        trace!("regist: {:?}, {:?}, {:?}", token, interest, opts);
+        Ok(())
     }
 }
 
@@ -1,26 +1,156 @@
 
 /*written by kimikan, 2017-7-12*/
-use std::sync::Arc;
 use std::io;
 
-use connection::Connection;
+use context::HandlerContext;
+use error::{self, Error};
 
-//will implement later
-//to integrete with the codec module
-//or just self-define it.
+//turns a frame body into an application message and back.
+//the length prefix is handled by the connection,
+//so a codec only sees whole frame bodies
 pub trait MessageCodec {
-    fn encode(&self, _: Arc<Vec<u8>>);
+    type Item;
+    //a decode error closes the connection as a protocol error
+    type Error: Into<Box<dyn std::error::Error + Send + Sync>>;
 
-    fn decode(&self, _: Arc<Vec<u8>>);
+    //a frame body received from the peer, it's borrowed
+    //from the connection's read buffer, copy what's kept
+    fn decode(&self, frame: &[u8]) -> Result<Self::Item, Self::Error>;
+
+    //a frame body to be sent to the peer
+    fn encode(&self, item: Self::Item) -> Result<Vec<u8>, Self::Error>;
+}
+
+//the message is the frame body itself
+pub struct RawCodec;
+
+impl MessageCodec for RawCodec {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn decode(&self, frame: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(frame.to_vec())
+    }
+
+    fn encode(&self, item: Vec<u8>) -> io::Result<Vec<u8>> {
+        Ok(item)
+    }
+}
+
+//the decoded message type of a handler
+pub type Item<T> = <<T as MessageHandler>::Codec as MessageCodec>::Item;
+
+//why a connection was closed,
+//passed to MessageHandler::on_disconnected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    //the peer closed the connection cleanly, its fin came
+    //between two messages
+    PeerClosed,
+    //the peer reset the connection, or it's gone while
+    //being written to
+    PeerReset,
+    //the socket hung up without a fin or a reset read
+    PeerHangup,
+    //reading or writing the socket failed
+    IoError(io::ErrorKind),
+    //the peer sent something can't be decoded,
+    //or closed in the middle of a message
+    ProtocolError,
+    //the handler closed it, or returned an error
+    HandlerRequested,
+    //a graceful close didn't finish in the linger timeout, the
+    //peer didn't take the replies or didn't send its fin
+    LingerTimeout,
+    //the server is going down
+    ServerShutdown,
+}
+
+impl DisconnectReason {
+    pub fn from_error(e: &Error) -> DisconnectReason {
+        match *e {
+            Error::Framing(_) |
+            Error::OversizedFrame(_) |
+            Error::Codec(_) => DisconnectReason::ProtocolError,
+            Error::Handler(_) => DisconnectReason::HandlerRequested,
+            Error::Io(ref e) => match e.kind() {
+                io::ErrorKind::UnexpectedEof => DisconnectReason::PeerClosed,
+                io::ErrorKind::ConnectionReset |
+                io::ErrorKind::ConnectionAborted |
+                io::ErrorKind::BrokenPipe => DisconnectReason::PeerReset,
+                kind => DisconnectReason::IoError(kind),
+            },
+            ref e => DisconnectReason::IoError(e.kind()),
+        }
+    }
+
+    //a close nobody did on purpose,
+    //it's worth more attention in the log
+    pub fn is_abnormal(&self) -> bool {
+        !matches!(*self, DisconnectReason::PeerClosed |
+                         DisconnectReason::HandlerRequested |
+                         DisconnectReason::ServerShutdown)
+    }
+}
+
+//what the server does with the connection,
+//after on_message_received returns
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandlerAction {
+    //go on with the next message
+    Continue,
+    //stop reading the connection, until it's resumed
+    //by HandlerContext::resume_reading or Context::resume_reading
+    PauseReading,
+    //close it now, the unsent messages are dropped
+    Close,
+    //stop handling its messages, close it once the queued
+    //messages are written, see Connection::close_gracefully
+    CloseAfterFlush,
 }
 
 //message handler, is a interface to
 //process the coming message
+//all of the lifecycle callbacks are optional
+
+pub trait MessageHandler: Sized {
+    //per client state, like auth identity, nickname, counters.
+    //a default one is created on accept, fill it in on_connected,
+    //get it by ctx.connection().session() in any callback
+    type Session: Default + Send;
+
+    //decodes the received frames, encodes the sent messages
+    type Codec: MessageCodec;
+
+    //a new client is accepted and registered,
+    //it's the first callback of the connection
+    fn on_connected(&self, _: &HandlerContext<Self>) {}
 
-pub trait MessageHandler {
     //this function defines that the callback function
-    //while there is new message received
-    //general logic, is parse it, then use context.send_message(token, message);
-    //to response the client.
-    fn on_message_received(&self, _: &Connection, _: &Arc<Vec<u8>>) -> io::Result<()>;
+    //while there is new message received, it's decoded already.
+    //general logic, is handle it, then use ctx.send(message);
+    //to response the client, or ctx.send_to another one.
+    //an error closes the connection at once, like HandlerAction::Close,
+    //wrap the handler's own errors by Error::handler
+    fn on_message_received(&self, _: &HandlerContext<Self>, _: Item<Self>) -> error::Result<HandlerAction>;
+
+    //the peer sent a message longer than the max frame len,
+    //what happens next is up to the server's OversizePolicy
+    fn on_oversized_frame(&self, _: &HandlerContext<Self>, _len: usize) {}
+
+    //true means the unsent messages of the connection went above
+    //a high watermark, and reading from it is paused.
+    //false means they are below the low watermarks again
+    fn on_backpressure(&self, _: &HandlerContext<Self>, _paused: bool) {}
+
+    //a timer scheduled by HandlerContext::schedule is due
+    fn on_timer(&self, _: &HandlerContext<Self>, _timer: usize) {}
+
+    //the connection is removed already,
+    //it's the last callback of the connection
+    fn on_disconnected(&self, _: &HandlerContext<Self>, _: DisconnectReason) {}
+
+    //reading or writing the connection failed,
+    //on_disconnected follows
+    fn on_error(&self, _: &HandlerContext<Self>, _: &Error) {}
 }
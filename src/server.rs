@@ -1,31 +1,142 @@
 
 /*written by kimikan, 2017-7-12*/
 use std::sync::{Arc};
+use std::collections::{HashSet, VecDeque};
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+use std::mem;
+use std::cmp;
 use std::io;
-use std::io::{Error, ErrorKind};
+use std::io::ErrorKind;
 use mio::{Event, Events, Token, Evented};
 use mio::net::TcpListener;
 use mio::unix::UnixReady;
-use context::Context;
+use mio::net::TcpStream;
+use context::{Context, Clients, HandlerContext};
+use connection::{self, Connection, ConnectionId, Inbound, Watermarks, SocketOptions};
 use serialize;
+use serialize::{DisconnectReason, HandlerAction};
+use framing::LengthDelimited;
+use error::{self, Error};
 use poll;
+use slab;
 
-//must be less than this
-pub const SERVERTOKEN: Token = Token(1000_000);
+//the client tokens never reach the top bit,
+//see connection::ConnectionId, mio keeps usize::MAX itself
+//the poller's notifier
+pub(crate) const NOTIFYTOKEN: Token = Token(usize::MAX - 1);
+//the first listener, the others count down from it
+pub(crate) const SERVERTOKEN: Token = Token(usize::MAX - 2);
+//how many clients a poll returns at most, by default
+pub const EVENTS_CAPACITY: usize = 1024;
+
+//how long a gracefully closed client has to take its
+//last replies and send its fin
+pub const LINGER_TIMEOUT: Duration = Duration::from_secs(5);
+
+//what a worker did, ServerHandle::join sums them up
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ServerStats {
+    _accepted: usize,
+    //over the limits on accept
+    _rejected: usize,
+    _disconnected: usize,
+    _messages: usize,
+    //still connected at the shutdown deadline
+    _forced_closes: usize,
+}
+
+impl ServerStats {
+    pub fn accepted(&self) -> usize {
+        self._accepted
+    }
+
+    pub fn rejected(&self) -> usize {
+        self._rejected
+    }
+
+    pub fn disconnected(&self) -> usize {
+        self._disconnected
+    }
+
+    //handed to the handler
+    pub fn messages(&self) -> usize {
+        self._messages
+    }
+
+    pub fn forced_closes(&self) -> usize {
+        self._forced_closes
+    }
+
+    pub(crate) fn merge(&mut self, other: &ServerStats) {
+        self._accepted += other._accepted;
+        self._rejected += other._rejected;
+        self._disconnected += other._disconnected;
+        self._messages += other._messages;
+        self._forced_closes += other._forced_closes;
+    }
+}
+
+//what to do with a message longer than the max frame len,
+//the handler is told by on_oversized_frame first
+#[derive(Clone, Debug)]
+pub enum OversizePolicy {
+    //close the connection as a protocol error
+    Close,
+    //skip the message, and reply with this frame
+    ErrorFrame(Arc<Vec<u8>>),
+    //skip the message silently
+    Discard,
+}
 
 //#[derive(Clone)]
-pub struct Server {
-    //server token.   1000000
-    _token: Token,
+pub struct Server<T: serialize::MessageHandler + Sized> {
     _events: Events,
+    //the longest a poll waits, none means until some event comes
+    _poll_timeout: Option<Duration>,
 
     //a listener repreenst a fd.
     //so it can be safely copyed.
-    _listener:TcpListener,
+    //the token of listener i is SERVERTOKEN - i
+    _listeners: Vec<TcpListener>,
 
     //listener fd should be registered 
     //into every poller instance. 
     _poller:poll::Poller,
+
+    //index in the context's registry
+    _worker: usize,
+    //clients accepted by this worker
+    _conns: Clients<T>,
+    //current generation of every slot
+    _generations: Vec<usize>,
+    _codec: Arc<T::Codec>,
+    //the length prefix of the clients of listener i
+    _framings: Vec<LengthDelimited>,
+    _oversize_policy: OversizePolicy,
+    _watermarks: Watermarks,
+    _socket_options: SocketOptions,
+
+    //hold the notified flushes for a while, so a chatty handler's
+    //messages go out together in fewer writes. none means no delay
+    _coalesce_delay: Option<Duration>,
+    _delayed: Vec<Token>,
+    _flush_deadline: Option<Instant>,
+
+    //scheduled by the handlers of this worker's clients
+    _timers: RefCell<poll::Timers>,
+
+    //the gracefully closed clients flushing or waiting for the
+    //peer's fin, by deadline, the linger timeout is the same for
+    //all of them
+    _linger_timeout: Duration,
+    _lingering: VecDeque<(Instant, Token)>,
+
+    //the shutdown deadline, once this worker is draining
+    _shutdown: Option<Instant>,
+    //the clients the drain closed, not the handler
+    _drained: HashSet<Token>,
+    _stats: ServerStats,
 }
 
 /*
@@ -41,56 +152,141 @@ impl<T: serialize::MessageHandler + Sized> Clone for Server<T> {
     }
 } */
 
-impl Server {
-    pub fn new(listener:TcpListener) -> Option<Self> {
+impl<T: serialize::MessageHandler + Sized> Server<T> {
+    //every server is a worker of the context, it accepts
+    //the clients of every listener, see ServerBuilder
+    pub fn new(listeners: Vec<TcpListener>, ctx:&Context<T>) -> error::Result<Self> {
         let p = poll::Poller::new();
         if let Ok(poll) = p {
-            return Some(Server {
-                _token: SERVERTOKEN,
-                _events: Events::with_capacity(1024),
-                _listener:listener,
+            let (worker, conns) = ctx.add_worker(poll.notifier());
+            return Ok(Server {
+                _events: Events::with_capacity(EVENTS_CAPACITY),
+                _poll_timeout: None,
+                _framings: vec![LengthDelimited::default(); listeners.len()],
+                _listeners: listeners,
                 _poller:poll,
+                _worker: worker,
+                _conns: conns,
+                _generations: vec![0; ctx._capacity],
+                _codec: ctx._codec.clone(),
+                _oversize_policy: OversizePolicy::Close,
+                _watermarks: Watermarks::default(),
+                _socket_options: SocketOptions::default(),
+                _coalesce_delay: None,
+                _delayed: vec![],
+                _flush_deadline: None,
+                _timers: RefCell::new(poll::Timers::default()),
+                _linger_timeout: LINGER_TIMEOUT,
+                _lingering: VecDeque::new(),
+                _shutdown: None,
+                _drained: HashSet::new(),
+                _stats: ServerStats::default(),
             });
         }
-        None
+        Err(Error::Io(p.err().unwrap()))
+    }
+
+    //the clients accepted later from listener index use this framing
+    pub fn set_framing(&mut self, index: usize, framing: LengthDelimited) -> error::Result<()> {
+        match self._framings.get_mut(index) {
+            Some(f) => *f = framing,
+            None => return Err(Error::InvalidConfig("no listener of the index")),
+        }
+        Ok(())
     }
 
-    pub fn poll_once(&mut self) -> io::Result<usize> {
-        self._poller.poll_once(&mut self._events)
+    //the max frame len is set by the framing
+    pub fn set_oversize_policy(&mut self, policy: OversizePolicy) {
+        self._oversize_policy = policy;
     }
 
-    //th context need a ?sized, handler 
-    pub fn unregister_token<T>(&self, ctx:&Context<T>, token:Token)->io::Result<()> 
-        where T:serialize::MessageHandler + Sized {
-        
-        let clients = ctx._conns.read().unwrap();
-        let client = clients.get(token);
-        if let Some(c) = client {
+    //the clients accepted later use these watermarks
+    pub fn set_watermarks(&mut self, watermarks: Watermarks) {
+        self._watermarks = watermarks;
+    }
+
+    //set on the clients accepted later
+    pub fn set_socket_options(&mut self, options: SocketOptions) {
+        self._socket_options = options;
+    }
+
+    //how many events a poll takes at most
+    pub fn set_events_capacity(&mut self, capacity: usize) {
+        self._events = Events::with_capacity(capacity);
+    }
+
+    //the worker wakes up at least this often
+    pub fn set_poll_timeout(&mut self, timeout: Option<Duration>) {
+        self._poll_timeout = timeout;
+    }
+
+    //the queued messages wait at most this long to be written,
+    //a writable edge after wouldblock is never delayed
+    pub fn set_coalesce_delay(&mut self, delay: Option<Duration>) {
+        self._coalesce_delay = delay;
+    }
+
+    //the gracefully closed clients are freed after this long,
+    //if they didn't take their replies and send the fin by then
+    pub fn set_linger_timeout(&mut self, timeout: Duration) {
+        self._linger_timeout = timeout;
+    }
+
+    pub(crate) fn poll_once(&mut self) -> error::Result<usize> {
+        //wake up in time for the delayed flushes, the timers,
+        //the lingering clients and the shutdown
+        let deadline = [
+            self._flush_deadline,
+            self._timers.borrow().next_deadline(),
+            self._lingering.front().map(|l| l.0),
+            self._shutdown,
+        ].iter().flatten().min().cloned();
+        let timeout = match (deadline, self._poll_timeout) {
+            (Some(deadline), Some(t)) => Some(cmp::min(deadline.saturating_duration_since(Instant::now()), t)),
+            (Some(deadline), None) => Some(deadline.saturating_duration_since(Instant::now())),
+            (None, t) => t,
+        };
+        Ok(self._poller.poll_once(&mut self._events, timeout)?)
+    }
+
+    pub(crate) fn unregister_token(&self, token:Token)->error::Result<()> {
+        if let Some(c) = self.find_client(token) {
             //deregister self from selected poller
-            return self._poller.deregister(&c._stream);
+            self._poller.deregister(&c._stream)?;
         }
         
         Ok(())
     }
 
     #[allow(dead_code)]
-    pub fn unregister<E: ?Sized>(&self, handle:&E)->io::Result<()> 
-        where E:Evented {
+    pub(crate) fn unregister<E>(&self, handle:&E)->error::Result<()> 
+        where E:Evented + ?Sized {
             //unregistered self
-        self._poller.deregister(handle)
+        Ok(self._poller.deregister(handle)?)
     }
 
-    pub fn register_read(&self, token: Token) -> io::Result<()> {    
+    pub(crate) fn register_read(&self, index: usize) -> error::Result<()> {    
         //Listener token will be registered into every poller instance
         //in every server=====, in case of thread panic
-        self._poller.register_read(&self._listener, token)
+        let token = Token(SERVERTOKEN.0 - index);
+        Ok(self._poller.register_read(&self._listeners[index], token)?)
     }
 
-    pub fn run<T>(&mut self, ctx: &Context<T>)  -> io::Result<()>
-        where T: serialize::MessageHandler + Sized {
+    //the index of the listener of the token
+    fn listener_of(&self, token: Token) -> Option<usize> {
+        SERVERTOKEN.0.checked_sub(token.0)
+            .filter(|i| *i < self._listeners.len())
+    }
+
+    //returns what the worker did, once Context::shutdown
+    //is called and its clients are all closed
+    pub fn run(&mut self, ctx: &Context<T>)  -> error::Result<ServerStats> {
         
         //every run thread instance, need firstly registered
-        self.register_read(self._token)?;
+        for i in 0..self._listeners.len() {
+            self.register_read(i)?;
+        }
+        self._poller.register_notifier(NOTIFYTOKEN)?;
 
         loop {
             let size = self.poll_once()?;
@@ -101,135 +297,534 @@ impl Server {
                     //forward and handle event seperately
                     self.on_event(ctx, &event);
                 } else {
-                    println!("errror event");
+                    error!("error event");
                     break;
                 }
             }//end for?
+
+            self.flush_delayed(ctx);
+            self.fire_timers(ctx);
+            self.expire_lingering(ctx);
+
+            if let Some(deadline) = ctx.shutdown_deadline() {
+                if self.drain(ctx, deadline) {
+                    info!("worker {} stopped", self._worker);
+                    return Ok(self._stats);
+                }
+            }
         }
     }
 
-    pub fn register_token<T>(&mut self, ctx:&Context<T>, token: Token) -> io::Result<()> 
-        where T: serialize::MessageHandler + Sized {
-        let clients = ctx._conns.read().unwrap();
-        
-        if let Some(c) = clients.get(token) {
+    /*
+     * the listeners are dropped first, then the
+     * clients are closed gracefully. the ones left at the deadline
+     * are closed anyway. true once no client is left
+     */
+    fn drain(&mut self, ctx: &Context<T>, deadline: Instant) -> bool {
+        if self._shutdown.is_none() {
+            for listener in &self._listeners {
+                if let Err(e) = self._poller.deregister(listener) {
+                    warn!("deregister listener failed: {:?}", e);
+                }
+            }
+            //the socket is closed with the last clone
+            self._listeners.clear();
+            let clients: Vec<_> = self._conns.read().unwrap().iter().cloned().collect();
+            for c in clients {
+                if c.start_close() {
+                    self._drained.insert(c.get_token());
+                }
+            }
+        }
+        //shutdown may bring the deadline forward
+        self._shutdown = Some(deadline);
+
+        if Instant::now() >= deadline {
+            let tokens: Vec<_> = self._conns.read().unwrap().iter()
+                .map(|c| c.get_token())
+                .collect();
+            for t in tokens {
+                self._stats._forced_closes += 1;
+                self.close_client(ctx, t, DisconnectReason::ServerShutdown);
+            }
+        }
+        self._conns.read().unwrap().is_empty()
+    }
+
+    //the peers not taking the replies or not sending their fin
+    //in time are closed anyway
+    fn expire_lingering(&mut self, ctx: &Context<T>) {
+        let now = Instant::now();
+        while let Some(&(deadline, token)) = self._lingering.front() {
+            if deadline > now {
+                break;
+            }
+            self._lingering.pop_front();
+            //nothing happens if the fin came already
+            self.close_client(ctx, token, DisconnectReason::LingerTimeout);
+        }
+    }
+
+    //call the handler with the due timers, the ones
+    //of a removed client are dropped
+    fn fire_timers(&mut self, ctx: &Context<T>) {
+        let expired = self._timers.borrow_mut().expired(Instant::now());
+        for (token, timer) in expired {
+            if let Some(c) = self.find_client(token) {
+                let handler = ctx._handle.read().unwrap();
+                handler.on_timer(&HandlerContext::new(ctx, &c, &self._timers), timer);
+            }
+        }
+    }
+
+    //write the delayed clients out, once the delay is over
+    fn flush_delayed(&mut self, ctx: &Context<T>) {
+        match self._flush_deadline {
+            Some(deadline) if deadline <= Instant::now() => {}
+            _ => return,
+        }
+
+        self._flush_deadline = None;
+        for token in mem::take(&mut self._delayed) {
+            if let Err(reason) = self.dispatch_write(token, ctx) {
+                self.close_client(ctx, token, reason);
+            }
+        }
+    }
+
+    pub(crate) fn register_token(&mut self, token: Token) -> error::Result<()> {
+        if let Some(c) = self.find_client(token) {
             c.register(&mut self._poller)?;
             return Ok(());
         }
 
-        Err(Error::new(ErrorKind::InvalidInput, "Invalid token"))
+        Err(Error::UnknownConnection(ConnectionId::from_token(self._worker, token)))
     }
 
-    fn on_event<T>(&mut self, ctx: &Context<T>, event: &Event)
-        where T : serialize::MessageHandler + Sized {
+    fn on_event(&mut self, ctx: &Context<T>, event: &Event) {
         let ready = UnixReady::from(event.readiness());
         let token = event.token();
         if ready.is_error() {
-            if let Err(e) = self.unregister_token(ctx, token) {
-                //strictly we should let it panic, but, it should recover
-                println!("-----------------------{:?}", e);
-            }
-
-            ctx.remove_client(token);
-            println!("error event recv");
+            debug!("error event recv, token={:?}", token);
+            let reason = self.take_socket_error(ctx, token);
+            self.close_client(ctx, token, reason);
             return;
         }
 
-        let mut vec: Vec<Token> = vec![];
-        if ready.is_readable() {
-            if token == self._token {
-                println!("new client connected");
-                self.on_accept(ctx);
+        let mut vec: Vec<(Token, DisconnectReason)> = vec![];
+        if token == NOTIFYTOKEN {
+            //messages queued while the socket may be writable already,
+            //the edge won't come again, so flush them now, or
+            //after the coalesce delay
+            let mut pending = self._poller.notifier().take_pending();
+            let mut resumed = vec![];
+            let mut closing = vec![];
+            //the clients asked to be closed don't wait
+            pending.retain(|t| match self.find_client(*t) {
+                Some(c) if c.is_close_requested() => {
+                    vec.push((*t, DisconnectReason::HandlerRequested));
+                    false
+                }
+                Some(c) => {
+                    if c.take_resumed() {
+                        resumed.push(*t);
+                    }
+                    if c.take_close_started() {
+                        closing.push(*t);
+                    }
+                    true
+                }
+                None => true,
+            });
+            let deadline = Instant::now() + self._linger_timeout;
+            self._lingering.extend(closing.into_iter().map(|t| (deadline, t)));
+            if let Some(delay) = self._coalesce_delay {
+                self._delayed.extend(pending);
+                if self._flush_deadline.is_none() {
+                    self._flush_deadline = Some(Instant::now() + delay);
+                }
             } else {
-                println!("forward read, token={:?}", token);
-                match self.dispatch_read(token, ctx) {
-                    Err(_) => {
-                        //cache it and remove it later
-                        vec.push(token);
+                for t in pending {
+                    if let Err(reason) = self.dispatch_write(t, ctx) {
+                        vec.push((t, reason));
                     }
-                    Ok(_) => {}
+                }
+            }
+
+            //no readable edge comes for what arrived while paused
+            for t in resumed {
+                if let Err(reason) = self.dispatch_read(t, ctx) {
+                    vec.push((t, reason));
+                }
+            }
+        } else if ready.is_readable() {
+            if let Some(index) = self.listener_of(token) {
+                trace!("accept on listener {}", index);
+                self.on_accept(ctx, index);
+            } else {
+                trace!("forward read, token={:?}", token);
+                if let Err(reason) = self.dispatch_read(token, ctx) {
+                    //cache it and remove it later
+                    vec.push((token, reason));
                 }
             }
         } //end
 
-        if ready.is_writable() {
-            let mut conns = ctx._conns.write().unwrap();
-            let client_op = conns.get_mut(token);
-            if let Some(mut c) = client_op {
-                println!("client write event, token={:?}", token);
-                c.on_write()
-                    .unwrap_or_else(|_| { vec.push(c.get_token()); });
+        if ready.is_writable() && token != NOTIFYTOKEN {
+            trace!("client write event, token={:?}", token);
+            if let Err(reason) = self.dispatch_write(token, ctx) {
+                vec.push((token, reason));
             }
         }
 
-        //unregister and remove. lifetime
-        for token in vec {
-            if let Err(e) = self.unregister_token(ctx, token) {
-                println!("-----------------------{:?}", e);
-            }
+        //a fin comes as readable and hup, the read above got the eof,
+        //or the paused client gets it once resumed, its queued
+        //replies still go out meanwhile. a bare hup means the
+        //socket is gone with nothing to read
+        if ready.is_hup() && !ready.is_readable() && token != NOTIFYTOKEN && self.listener_of(token).is_none() {
+            debug!("hup event recv, token={:?}", token);
+            vec.push((token, DisconnectReason::PeerHangup));
+        }
 
-            ctx.remove_client(token);
+        //unregister and remove. lifetime
+        //a token may be here twice, only the first one counts
+        for (token, reason) in vec {
+            self.close_client(ctx, token, reason);
         }
     }
 
     //new client connected handler
-    fn on_accept<T>(&mut self, ctx: &Context<T>)
-        where T : serialize::MessageHandler + Sized {
+    fn on_accept(&mut self, ctx: &Context<T>, index: usize) {
         loop {
-            let accept_result = self._listener.accept();
+            let accept_result = self._listeners[index].accept();
             //println!("get one client");
             let client = match accept_result {
                 Ok((c, _)) => c,
                 Err(e) => {
                     if e.kind() != ErrorKind::WouldBlock {
-                        println!("accept error");
+                        warn!("accept error: {:?}", e);
                     }
                     //println!("accept wuld block, {:?}", e);
                     return;
                 }
             };
-            let token = ctx.available_token(client);
-            if let Some(t) = token {
-                println!("client added:......");
-                self.register_token(ctx, t).expect("register client failed");
+            if let Err(e) = self._socket_options.apply(&client) {
+                warn!("client socket options failed: {:?}", e);
+            }
+            //the global limit of the context
+            if !ctx.add_client() {
+                self._stats._rejected += 1;
+                warn!("client dropped: {}", Error::CapacityExhausted);
+                continue;
+            }
+            match self.available_token(client, index) {
+                Ok(t) => {
+                    //it's in the slab and counted already, undo both
+                    if let Err(e) = self.register_token(t) {
+                        self.remove_client(t);
+                        ctx.remove_client();
+                        self._stats._rejected += 1;
+                        warn!("client dropped, register failed: {}", e);
+                        continue;
+                    }
+                    self._stats._accepted += 1;
+                    debug!("client added, token={:?}", t);
+
+                    if let Some(c) = self.find_client(t) {
+                        let handler = ctx._handle.read().unwrap();
+                        handler.on_connected(&HandlerContext::new(ctx, &c, &self._timers));
+                    }
+                }
+                Err(e) => {
+                    ctx.remove_client();
+                    self._stats._rejected += 1;
+                    warn!("client dropped: {}", e);
+                }
+            }
+        }
+    }
+
+    //the error of an error event, it's reported to the handler
+    fn take_socket_error(&self, ctx: &Context<T>, token: Token) -> DisconnectReason {
+        let client = match self.find_client(token) {
+            Some(c) => c,
+            None => return DisconnectReason::IoError(ErrorKind::Other),
+        };
+
+        let error = match client._stream.take_error() {
+            Ok(Some(e)) => Error::Io(e),
+            Ok(None) => Error::Io(io::Error::other("error event without socket error")),
+            Err(e) => Error::Io(e),
+        };
+        let handler = ctx._handle.read().unwrap();
+        handler.on_error(&HandlerContext::new(ctx, &client, &self._timers), &error);
+        DisconnectReason::from_error(&error)
+    }
+
+    //unregister and remove the client, then tell the handler.
+    //nothing happens if it's removed already
+    fn close_client(&mut self, ctx: &Context<T>, token: Token, reason: DisconnectReason) {
+        let drained = self._drained.remove(&token);
+        if let Err(e) = self.unregister_token(token) {
+            //strictly we should let it panic, but, it should recover
+            error!("unregister failed, token={:?}: {:?}", token, e);
+        }
+
+        if let Some(c) = self.remove_client(token) {
+            //the graceful closes the drain started end up here as finished
+            //or timed out ones, a close asked for meanwhile keeps its reason
+            let reason = match reason {
+                DisconnectReason::HandlerRequested |
+                DisconnectReason::LingerTimeout if drained && !c.is_close_requested() => {
+                    DisconnectReason::ServerShutdown
+                }
+                r => r,
+            };
+            ctx.remove_client();
+            self._stats._disconnected += 1;
+            if reason.is_abnormal() {
+                warn!("client removed, token={:?}, reason={:?}", token, reason);
             } else {
-                println!("no available token found");
+                debug!("client removed, token={:?}, reason={:?}", token, reason);
+            }
+            let handler = ctx._handle.read().unwrap();
+            handler.on_disconnected(&HandlerContext::new(ctx, &c, &self._timers), reason);
+        }
+    }
+
+    /*
+     * the client of the token, a late event or a stored token
+     * of a removed client doesn't match the slot's new owner.
+     * the slab is locked only to clone the client, the io and
+     * the handler run without it, so the handler can send to
+     * any client, or accept on other workers meanwhile
+     */
+    fn find_client(&self, token: Token) -> Option<Arc<Connection<T>>> {
+        let clients = self._conns.read().unwrap();
+        Self::lookup(&clients, token).cloned()
+    }
+
+    fn lookup(clients: &slab::Slab<Arc<Connection<T>>, usize>, token: Token) -> Option<&Arc<Connection<T>>> {
+        clients.get(connection::slot_of(token))
+            .filter(|c| c.get_token() == token)
+    }
+
+    fn remove_client(&mut self, token: Token) -> Option<Arc<Connection<T>>> {
+        let mut clients = self._conns.write().unwrap();
+        Self::lookup(&clients, token)?;
+
+        let slot = connection::slot_of(token);
+        //the old id is stale from now on
+        self._generations[slot] = ConnectionId::next_generation(self._generations[slot]);
+        clients.remove(slot)
+    }
+
+    //the client gets the framing of the listener it came from
+    fn available_token(&self, client: TcpStream, index: usize) -> error::Result<Token> {
+        let mut conns = self._conns.write().unwrap();
+        let entry_op = conns.vacant_entry();
+        let token = match entry_op {
+            Some(e) => {
+                let slot = e.index();
+                let id = ConnectionId::new(self._worker, slot, self._generations[slot]);
+                let connection = Connection::new(client, id, self._poller.notifier(),
+                                                 self._codec.clone(), self._framings[index],
+                                                 self._watermarks);
+                e.insert(Arc::new(connection));
+                id.token()
+            }
+            None => {
+                debug!("no empty entry for new clients");
+                return Err(Error::CapacityExhausted);
+            }
+        };
+
+        Ok(token)
+    }
+
+    //flush the queued messages of the client
+    fn dispatch_write(&mut self, token: Token, ctx: &Context<T>) -> Result<(), DisconnectReason> {
+        let client = match self.find_client(token) {
+            Some(c) => c,
+            //it's removed already, nothing to flush
+            None => return Ok(()),
+        };
+
+        let hctx = HandlerContext::new(ctx, &client, &self._timers);
+        client.on_write().map_err(|e| {
+            let handler = ctx._handle.read().unwrap();
+            handler.on_error(&hctx, &e);
+            DisconnectReason::from_error(&e)
+        })?;
+
+        //the graceful close, the last reply is out,
+        //send the fin and wait for the peer's one
+        if client.is_closing() && client.pending() == (0, 0) && !client.is_write_shutdown() {
+            debug!("client flushed, shutdown write, token={:?}", token);
+            let shut = client.shutdown_write().map_err(|e| DisconnectReason::from_error(&e))?;
+            //the linger timeout runs since close_gracefully
+            if shut && client.is_read_eof() {
+                return Err(DisconnectReason::HandlerRequested);
             }
         }
+
+        let resumed = client.is_backpressured() && client.is_drained();
+        if resumed {
+            debug!("client drained, resume reading, token={:?}", token);
+            client.set_backpressured(false);
+            let handler = ctx._handle.read().unwrap();
+            handler.on_backpressure(&hctx, false);
+        }
+
+        //no readable edge comes for what's already in the socket
+        if resumed {
+            self.dispatch_read(token, ctx)?;
+        }
+        Ok(())
     }
 
     //dispatcher all of the read events to registered handler
-    fn dispatch_read<T>(&mut self, token: Token, ctx: &Context<T>) -> io::Result<()>
-        where T : serialize::MessageHandler + Sized {
-        let mut conns = ctx._conns.write().unwrap();
-        let client_op = conns.get_mut(token);
-        let client = match client_op {
+    fn dispatch_read(&mut self, token: Token, ctx: &Context<T>) -> Result<(), DisconnectReason> {
+        let client = match self.find_client(token) {
             Some(expr) => expr,
             None => {
-                println!("no client got:{:?}", token);
-                return Err(Error::new(ErrorKind::InvalidData, "invlid token"));
+                //a late event of a removed client
+                trace!("no client got:{:?}", token);
+                return Ok(());
             }
         };
 
+        let hctx = HandlerContext::new(ctx, &client, &self._timers);
         loop {
-            let read_result = client.on_read();
-            if let Ok(read_op) = read_result {
-                if let Some(message) = read_op {
-                    let rc_message = Arc::new(message);
-                    println!("client send message start..");
+            //the handler closed it, the rest is not handled
+            if client.is_close_requested() {
+                return Err(DisconnectReason::HandlerRequested);
+            }
+            //no more message is handled, it's read only for the fin
+            if client.is_closing() {
+                return Self::linger_read(&client);
+            }
+            //it goes on when the handler resumes it
+            if client.is_paused() {
+                break;
+            }
+            //the peer doesn't read its replies, stop reading its requests.
+            //it goes on when dispatch_write drains the queue
+            if client.is_backpressured() {
+                break;
+            }
+            if client.is_congested() {
+                debug!("client congested, pause reading, token={:?}", token);
+                client.set_backpressured(true);
+                let handler = ctx._handle.read().unwrap();
+                handler.on_backpressure(&hctx, true);
+                break;
+            }
+
+            match client.on_read() {
+                Ok(Some(Inbound::Oversized(len))) => {
+                    debug!("forward read: oversized message, len={}", len);
+                    let handler = ctx._handle.read().unwrap();
+                    handler.on_oversized_frame(&hctx, len);
+                    match self._oversize_policy {
+                        OversizePolicy::Close => return Err(DisconnectReason::ProtocolError),
+                        OversizePolicy::ErrorFrame(ref frame) => {
+                            if let Err(e) = client.send_frame(frame.clone()) {
+                                warn!("forward read: error frame failed {:?}", e);
+                            }
+                        }
+                        OversizePolicy::Discard => {}
+                    }
+                }
+                Ok(Some(Inbound::Message(message))) => {
+                    self._stats._messages += 1;
+                    trace!("client send message start..");
                     // Queue up a write for all connected clients.
                     let handler = ctx._handle.read().unwrap();
-                    handler.on_message_received(&client, &rc_message)?
+                    match handler.on_message_received(&hctx, message) {
+                        Ok(HandlerAction::Continue) => {}
+                        Ok(HandlerAction::PauseReading) => client.pause_reading(),
+                        Ok(HandlerAction::Close) => return Err(DisconnectReason::HandlerRequested),
+                        Ok(HandlerAction::CloseAfterFlush) => client.close_gracefully(),
+                        Err(e) => {
+                            warn!("forward read: handler failed {:?}", e);
+                            handler.on_error(&hctx, &e);
+                            return Err(DisconnectReason::HandlerRequested);
+                        }
+                    }
                     //client.send_message(rc_message.clone());
-                } else {
-                    println!("forward read: no message got");
+                }
+                Ok(None) => {
+                    trace!("forward read: no message got");
                     break;
                 }
-            } else {
-                println!("forward read: read failed");
-                return Err(Error::new(ErrorKind::InvalidData, "read failed"));
+                Err(e) => {
+                    let reason = DisconnectReason::from_error(&e);
+                    if reason.is_abnormal() {
+                        warn!("forward read: read failed {:?}", e);
+                    }
+                    if reason != DisconnectReason::PeerClosed {
+                        let handler = ctx._handle.read().unwrap();
+                        handler.on_error(&hctx, &e);
+                    }
+                    return Err(reason);
+                }
             }
         }
         Ok(())
     }
+
+    //a closing client is done once the peer's fin came,
+    //and its own is sent
+    fn linger_read(client: &Connection<T>) -> Result<(), DisconnectReason> {
+        match client.discard_read() {
+            Ok(true) if client.is_write_shutdown() => Err(DisconnectReason::HandlerRequested),
+            Ok(_) => Ok(()),
+            Err(e) => Err(DisconnectReason::from_error(&e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net;
+    use std::sync::Mutex;
+    use std::sync::mpsc::{self, Sender};
+    use mio::Ready;
+    use context;
+    use serialize::RawCodec;
+
+    //tells the test why its clients are gone
+    struct Reasons(Mutex<Sender<DisconnectReason>>);
+
+    impl serialize::MessageHandler for Reasons {
+        type Session = ();
+        type Codec = RawCodec;
+
+        fn on_message_received(&self, _: &HandlerContext<Self>, _: Vec<u8>) -> error::Result<HandlerAction> {
+            Ok(HandlerAction::Continue)
+        }
+
+        fn on_disconnected(&self, _: &HandlerContext<Self>, reason: DisconnectReason) {
+            self.0.lock().unwrap().send(reason).unwrap();
+        }
+    }
+
+    //a hup without a readable edge can't be made on loopback,
+    //the events are fed to a worker not running
+    #[test]
+    fn bare_hup_is_a_hangup() {
+        let (tx, rx) = mpsc::channel();
+        let ctx = Context::new(Reasons(Mutex::new(tx)), RawCodec, 4).unwrap();
+        let listener = context::bind("127.0.0.1:0").unwrap();
+        let _peer = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut server = Server::new(vec![listener], &ctx).unwrap();
+
+        server.on_event(&ctx, &Event::new(Ready::readable(), SERVERTOKEN));
+        let token = server._conns.read().unwrap().iter().next().unwrap().get_token();
+        server.on_event(&ctx, &Event::new(UnixReady::hup().into(), token));
+        assert_eq!(rx.try_recv(), Ok(DisconnectReason::PeerHangup));
+        assert!(server._conns.read().unwrap().is_empty());
+    }
 }
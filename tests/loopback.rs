@@ -0,0 +1,555 @@
+//a whole server on loopback, driven through the public api
+extern crate miox;
+extern crate byteorder;
+extern crate libc;
+
+use std::io::{Read, Write};
+use std::mem;
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, BigEndian};
+use miox::{MessageHandler, HandlerContext, HandlerAction, DisconnectReason, ConnectionId,
+           Error, RawCodec, ServerBuilder, ServerHandle, Watermarks, SocketOptions,
+           LengthDelimited, OversizePolicy};
+
+struct Echo;
+
+impl MessageHandler for Echo {
+    type Session = ();
+    type Codec = RawCodec;
+
+    fn on_message_received(&self, ctx: &HandlerContext<Self>, message: Vec<u8>) -> miox::Result<HandlerAction> {
+        ctx.send(message)?;
+        Ok(HandlerAction::Continue)
+    }
+}
+
+//what a Recorder's callbacks saw
+#[derive(Debug, PartialEq)]
+enum Seen {
+    Connected,
+    Message(Vec<u8>),
+    Oversized(usize),
+    Backpressure(bool),
+    Error,
+    Disconnected(DisconnectReason),
+}
+
+type Reply = Box<dyn Fn(&HandlerContext<Recorder>, Vec<u8>) -> miox::Result<HandlerAction> + Send + Sync>;
+
+//sends every callback to the test, the messages are answered by reply
+struct Recorder {
+    _seen: Mutex<Sender<(ConnectionId, Seen)>>,
+    _reply: Reply,
+}
+
+impl Recorder {
+    fn new<F>(reply: F) -> (Recorder, Receiver<(ConnectionId, Seen)>)
+        where F: Fn(&HandlerContext<Recorder>, Vec<u8>) -> miox::Result<HandlerAction> + Send + Sync + 'static
+    {
+        let (tx, rx) = mpsc::channel();
+        (Recorder { _seen: Mutex::new(tx), _reply: Box::new(reply) }, rx)
+    }
+
+    fn seen(&self, ctx: &HandlerContext<Self>, seen: Seen) {
+        let _ = self._seen.lock().unwrap().send((ctx.id(), seen));
+    }
+}
+
+impl MessageHandler for Recorder {
+    type Session = ();
+    type Codec = RawCodec;
+
+    fn on_connected(&self, ctx: &HandlerContext<Self>) {
+        self.seen(ctx, Seen::Connected);
+    }
+
+    fn on_message_received(&self, ctx: &HandlerContext<Self>, message: Vec<u8>) -> miox::Result<HandlerAction> {
+        self.seen(ctx, Seen::Message(message.clone()));
+        (self._reply)(ctx, message)
+    }
+
+    fn on_oversized_frame(&self, ctx: &HandlerContext<Self>, len: usize) {
+        self.seen(ctx, Seen::Oversized(len));
+    }
+
+    fn on_backpressure(&self, ctx: &HandlerContext<Self>, paused: bool) {
+        self.seen(ctx, Seen::Backpressure(paused));
+    }
+
+    fn on_disconnected(&self, ctx: &HandlerContext<Self>, reason: DisconnectReason) {
+        self.seen(ctx, Seen::Disconnected(reason));
+    }
+
+    fn on_error(&self, ctx: &HandlerContext<Self>, _: &Error) {
+        self.seen(ctx, Seen::Error);
+    }
+}
+
+//the next callback, a stuck server fails the test
+fn next(seen: &Receiver<(ConnectionId, Seen)>) -> (ConnectionId, Seen) {
+    seen.recv_timeout(Duration::from_secs(5)).expect("no callback in time")
+}
+
+//nothing more came meanwhile
+fn quiet(seen: &Receiver<(ConnectionId, Seen)>, wait: Duration) {
+    if let Ok(s) = seen.recv_timeout(wait) {
+        panic!("unexpected callback {:?}", s);
+    }
+}
+
+//the reason of the next close, the errors before it are skipped
+fn disconnected(seen: &Receiver<(ConnectionId, Seen)>) -> (ConnectionId, DisconnectReason) {
+    loop {
+        match next(seen) {
+            (id, Seen::Disconnected(reason)) => return (id, reason),
+            (_, Seen::Error) => {}
+            s => panic!("unexpected callback {:?}", s),
+        }
+    }
+}
+
+//drop it with a reset instead of a fin
+fn reset(client: TcpStream) {
+    let linger = libc::linger { l_onoff: 1, l_linger: 0 };
+    let r = unsafe {
+        libc::setsockopt(client.as_raw_fd(), libc::SOL_SOCKET, libc::SO_LINGER,
+                         &linger as *const libc::linger as *const libc::c_void,
+                         mem::size_of::<libc::linger>() as libc::socklen_t)
+    };
+    assert_eq!(r, 0);
+}
+
+fn frame(body: &[u8]) -> Vec<u8> {
+    let mut f = vec![0u8; 8];
+    BigEndian::write_u64(&mut f, body.len() as u64);
+    f.extend_from_slice(body);
+    f
+}
+
+fn read_frame(client: &mut TcpStream) -> Vec<u8> {
+    let mut header = [0u8; 8];
+    client.read_exact(&mut header).unwrap();
+    let mut body = vec![0u8; BigEndian::read_u64(&header) as usize];
+    client.read_exact(&mut body).unwrap();
+    body
+}
+
+//true once the server's fin came
+fn read_eof(client: &mut TcpStream) -> bool {
+    let mut b = [0u8; 1];
+    client.read(&mut b).unwrap() == 0
+}
+
+#[test]
+fn echoes_fragmented_writes() {
+    let server = ServerBuilder::new(Echo, RawCodec)
+        .bind("127.0.0.1:0")
+        .with_workers(2)
+        .run()
+        .unwrap();
+
+    let bytes = [frame(b"hello"), frame(b""), frame(&vec![7u8; 200 * 1024])].concat();
+    let mut client = TcpStream::connect(server.local_addrs()[0]).unwrap();
+    client.set_nodelay(true).unwrap();
+
+    //the headers and the first bodies split everywhere,
+    //the big body in one go
+    for b in bytes[..40].iter() {
+        client.write_all(&[*b]).unwrap();
+        thread::sleep(Duration::from_millis(1));
+    }
+    client.write_all(&bytes[40..]).unwrap();
+
+    let mut echoed = vec![0u8; bytes.len()];
+    client.read_exact(&mut echoed).unwrap();
+    assert!(echoed == bytes);
+    drop(client);
+
+    server.shutdown(Duration::from_secs(1));
+    let stats = server.join().unwrap();
+    assert_eq!(stats.accepted(), 1);
+    assert_eq!(stats.messages(), 3);
+}
+
+//the reply goes out first, then the fin, the slot is freed
+//once the peer's fin came
+#[test]
+fn close_after_flush_waits_for_the_peer_fin() {
+    let (handler, seen) = Recorder::new(|ctx, _| {
+        ctx.send(b"bye".to_vec())?;
+        Ok(HandlerAction::CloseAfterFlush)
+    });
+    let server = ServerBuilder::new(handler, RawCodec)
+        .bind("127.0.0.1:0")
+        .with_workers(1)
+        .with_linger_timeout(Duration::from_secs(10))
+        .run()
+        .unwrap();
+
+    let mut client = TcpStream::connect(server.local_addrs()[0]).unwrap();
+    client.write_all(&frame(b"quit")).unwrap();
+    let (id, _) = next(&seen);
+    assert_eq!(next(&seen), (id, Seen::Message(b"quit".to_vec())));
+
+    assert_eq!(read_frame(&mut client), b"bye");
+    assert!(read_eof(&mut client));
+    //closing, nothing more is handled or sent
+    client.write_all(&frame(b"late")).unwrap();
+    match server.context().send_message_to_client(id, b"late".to_vec()) {
+        Err(Error::Closing(closing)) => assert_eq!(closing, id),
+        r => panic!("sent to a closing client: {:?}", r),
+    }
+    quiet(&seen, Duration::from_millis(200));
+
+    client.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(next(&seen), (id, Seen::Disconnected(DisconnectReason::HandlerRequested)));
+
+    server.shutdown(Duration::from_secs(1));
+    let stats = server.join().unwrap();
+    assert_eq!(stats.messages(), 1);
+    assert_eq!(stats.forced_closes(), 0);
+}
+
+//a peer holding its half open is dropped at the linger timeout
+#[test]
+fn close_after_flush_times_out_without_the_peer_fin() {
+    let linger = Duration::from_millis(300);
+    let (handler, seen) = Recorder::new(|ctx, _| {
+        ctx.send(b"bye".to_vec())?;
+        Ok(HandlerAction::CloseAfterFlush)
+    });
+    let server = ServerBuilder::new(handler, RawCodec)
+        .bind("127.0.0.1:0")
+        .with_workers(1)
+        .with_linger_timeout(linger)
+        .run()
+        .unwrap();
+
+    let mut client = TcpStream::connect(server.local_addrs()[0]).unwrap();
+    client.write_all(&frame(b"quit")).unwrap();
+    let (id, _) = next(&seen);
+    assert_eq!(next(&seen), (id, Seen::Message(b"quit".to_vec())));
+    let closed = Instant::now();
+
+    assert_eq!(read_frame(&mut client), b"bye");
+    assert!(read_eof(&mut client));
+    assert_eq!(next(&seen), (id, Seen::Disconnected(DisconnectReason::LingerTimeout)));
+    assert!(closed.elapsed() >= linger - Duration::from_millis(50));
+    drop(client);
+
+    server.shutdown(Duration::from_secs(1));
+    let stats = server.join().unwrap();
+    assert_eq!(stats.disconnected(), 1);
+}
+
+#[test]
+fn disconnect_reasons() {
+    let (handler, seen) = Recorder::new(|_, _| Ok(HandlerAction::Continue));
+    let server = ServerBuilder::new(handler, RawCodec)
+        .bind("127.0.0.1:0")
+        .with_workers(1)
+        .run()
+        .unwrap();
+    let addr = server.local_addrs()[0];
+
+    //a fin between two messages
+    let mut client = TcpStream::connect(addr).unwrap();
+    let (id, _) = next(&seen);
+    client.write_all(&frame(b"one")).unwrap();
+    assert_eq!(next(&seen), (id, Seen::Message(b"one".to_vec())));
+    drop(client);
+    assert_eq!(next(&seen), (id, Seen::Disconnected(DisconnectReason::PeerClosed)));
+
+    //a fin in the middle of the header, then of the body
+    for cut in [4, 10] {
+        let mut client = TcpStream::connect(addr).unwrap();
+        let (id, _) = next(&seen);
+        client.write_all(&frame(b"truncated")[..cut]).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(next(&seen), (id, Seen::Error));
+        assert_eq!(next(&seen), (id, Seen::Disconnected(DisconnectReason::ProtocolError)));
+    }
+
+    //a reset
+    let client = TcpStream::connect(addr).unwrap();
+    let (id, _) = next(&seen);
+    reset(client);
+    assert_eq!(disconnected(&seen), (id, DisconnectReason::PeerReset));
+
+    server.shutdown(Duration::from_secs(1));
+    let stats = server.join().unwrap();
+    assert_eq!(stats.disconnected(), 4);
+}
+
+//the closes the drain started are shutdowns, the ones the
+//handler asked for before or during it keep their reason
+#[test]
+fn shutdown_reports_only_its_own_closes() {
+    let (handler, seen) = Recorder::new(|_, message| {
+        if message == b"quit" {
+            return Ok(HandlerAction::CloseAfterFlush);
+        }
+        Ok(HandlerAction::Continue)
+    });
+    let server = ServerBuilder::new(handler, RawCodec)
+        .bind("127.0.0.1:0")
+        .with_workers(1)
+        .with_linger_timeout(Duration::from_secs(10))
+        .run()
+        .unwrap();
+    let addr = server.local_addrs()[0];
+
+    let mut quitting = TcpStream::connect(addr).unwrap();
+    let (quitting_id, _) = next(&seen);
+    let mut closed = TcpStream::connect(addr).unwrap();
+    let (closed_id, _) = next(&seen);
+    let mut drained = TcpStream::connect(addr).unwrap();
+    let (drained_id, _) = next(&seen);
+
+    quitting.write_all(&frame(b"quit")).unwrap();
+    assert_eq!(next(&seen), (quitting_id, Seen::Message(b"quit".to_vec())));
+    assert!(read_eof(&mut quitting));
+
+    server.shutdown(Duration::from_secs(10));
+    assert!(read_eof(&mut closed));
+    assert!(read_eof(&mut drained));
+
+    quitting.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(next(&seen), (quitting_id, Seen::Disconnected(DisconnectReason::HandlerRequested)));
+    server.context().close_client(closed_id).unwrap();
+    assert_eq!(next(&seen), (closed_id, Seen::Disconnected(DisconnectReason::HandlerRequested)));
+    drained.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(next(&seen), (drained_id, Seen::Disconnected(DisconnectReason::ServerShutdown)));
+
+    let stats = server.join().unwrap();
+    assert_eq!(stats.disconnected(), 3);
+    assert_eq!(stats.forced_closes(), 0);
+}
+
+//the replies queue up above the high watermark, reading stops
+//till they are written below the low one
+#[test]
+fn backpressure_pauses_a_client_not_reading() {
+    const MESSAGES: usize = 50;
+    const REPLY: usize = 64 * 1024;
+    let (handler, seen) = Recorder::new(|ctx, _| {
+        ctx.send(vec![7u8; REPLY])?;
+        Ok(HandlerAction::Continue)
+    });
+    let server = ServerBuilder::new(handler, RawCodec)
+        .bind("127.0.0.1:0")
+        .with_workers(1)
+        .with_watermarks(Watermarks::new(4 * REPLY, REPLY))
+        .with_socket_options(SocketOptions::default().with_send_buffer_size(4096))
+        .run()
+        .unwrap();
+
+    let mut client = TcpStream::connect(server.local_addrs()[0]).unwrap();
+    let (id, _) = next(&seen);
+    let bytes: Vec<u8> = (0..MESSAGES).flat_map(|i| frame(i.to_string().as_bytes())).collect();
+    client.write_all(&bytes).unwrap();
+
+    let mut handled = 0;
+    loop {
+        match next(&seen) {
+            (_, Seen::Message(_)) => handled += 1,
+            (_, Seen::Backpressure(true)) => break,
+            s => panic!("unexpected callback {:?}", s),
+        }
+    }
+    assert!(handled < MESSAGES);
+    quiet(&seen, Duration::from_millis(200));
+
+    let reader = thread::spawn(move || {
+        for _ in 0..MESSAGES {
+            assert_eq!(read_frame(&mut client).len(), REPLY);
+        }
+        client
+    });
+    //no message is handled while paused, it may pause again
+    let mut paused = true;
+    while handled < MESSAGES || paused {
+        match next(&seen) {
+            (_, Seen::Message(_)) => {
+                assert!(!paused);
+                handled += 1;
+            }
+            (_, Seen::Backpressure(p)) => {
+                assert_eq!(p, !paused);
+                paused = p;
+            }
+            s => panic!("unexpected callback {:?}", s),
+        }
+    }
+    drop(reader.join().unwrap());
+    assert_eq!(next(&seen), (id, Seen::Disconnected(DisconnectReason::PeerClosed)));
+
+    server.shutdown(Duration::from_secs(1));
+    let stats = server.join().unwrap();
+    assert_eq!(stats.messages(), MESSAGES);
+}
+
+//a client not sending its fin is closed at the shutdown deadline
+#[test]
+fn shutdown_forces_the_clients_left_at_the_deadline() {
+    let timeout = Duration::from_millis(300);
+    let (handler, seen) = Recorder::new(|_, _| Ok(HandlerAction::Continue));
+    let server = ServerBuilder::new(handler, RawCodec)
+        .bind("127.0.0.1:0")
+        .with_workers(2)
+        .with_linger_timeout(Duration::from_secs(10))
+        .run()
+        .unwrap();
+
+    let mut client = TcpStream::connect(server.local_addrs()[0]).unwrap();
+    let (id, _) = next(&seen);
+
+    let started = Instant::now();
+    server.shutdown(timeout);
+    assert!(read_eof(&mut client));
+    let stats = server.join().unwrap();
+    let took = started.elapsed();
+    assert!(took >= timeout - Duration::from_millis(50), "joined after {:?}", took);
+    assert!(took < timeout + Duration::from_secs(1), "joined after {:?}", took);
+
+    assert_eq!(next(&seen), (id, Seen::Disconnected(DisconnectReason::ServerShutdown)));
+    assert_eq!(stats.forced_closes(), 1);
+    assert_eq!(stats.disconnected(), 1);
+}
+
+//the other worker's client is reached from the test thread and
+//from a handler, its owner wakes up to write it out
+#[test]
+fn sends_across_workers() {
+    let ids: Arc<Mutex<Vec<ConnectionId>>> = Arc::default();
+    let peers = ids.clone();
+    let (handler, seen) = Recorder::new(move |ctx, message| {
+        //relayed to the clients of the other workers
+        for id in peers.lock().unwrap().iter().filter(|id| id.worker() != ctx.id().worker()) {
+            ctx.send_to(*id, message.clone())?;
+        }
+        Ok(HandlerAction::Continue)
+    });
+    let server = ServerBuilder::new(handler, RawCodec)
+        .bind("127.0.0.1:0")
+        .with_workers(2)
+        .with_max_clients_per_worker(1)
+        .run()
+        .unwrap();
+
+    //one client a worker, the full one drops the client it accepts
+    let mut clients = vec![];
+    while clients.len() < 2 {
+        let client = TcpStream::connect(server.local_addrs()[0]).unwrap();
+        match seen.recv_timeout(Duration::from_secs(1)) {
+            Ok((id, Seen::Connected)) => clients.push((client, id)),
+            Ok(s) => panic!("unexpected callback {:?}", s),
+            Err(_) => {}
+        }
+    }
+    let (mut b, b_id) = clients.pop().unwrap();
+    let (mut a, a_id) = clients.pop().unwrap();
+    assert!(a_id.worker() != b_id.worker());
+    *ids.lock().unwrap() = vec![a_id, b_id];
+
+    //from a thread of no worker
+    server.context().send_message_to_client(a_id, b"pushed a".to_vec()).unwrap();
+    server.context().send_message_to_client(b_id, b"pushed b".to_vec()).unwrap();
+    assert_eq!(read_frame(&mut a), b"pushed a");
+    assert_eq!(read_frame(&mut b), b"pushed b");
+
+    //from the handler of the other worker
+    a.write_all(&frame(b"to b")).unwrap();
+    assert_eq!(next(&seen), (a_id, Seen::Message(b"to b".to_vec())));
+    assert_eq!(read_frame(&mut b), b"to b");
+    b.write_all(&frame(b"to a")).unwrap();
+    assert_eq!(next(&seen), (b_id, Seen::Message(b"to a".to_vec())));
+    assert_eq!(read_frame(&mut a), b"to a");
+
+    //the stored id outlives its client
+    drop(a);
+    assert_eq!(next(&seen), (a_id, Seen::Disconnected(DisconnectReason::PeerClosed)));
+    match server.context().send_message_to_client(a_id, b"gone".to_vec()) {
+        Err(Error::UnknownConnection(id)) => assert_eq!(id, a_id),
+        r => panic!("sent to a gone client: {:?}", r),
+    }
+
+    drop(b);
+    server.shutdown(Duration::from_secs(1));
+    let stats = server.join().unwrap();
+    assert_eq!(stats.accepted(), 2);
+    assert_eq!(stats.messages(), 2);
+}
+
+const MAX_FRAME_LEN: usize = 16;
+//longer than a read, the skip goes on across reads
+const OVERSIZED: usize = 100 * 1024;
+
+//an echo server taking frames of MAX_FRAME_LEN at most, a client sent
+//a frame, an oversized one and another frame to it
+fn oversized(policy: OversizePolicy) -> (ServerHandle<Recorder>, Receiver<(ConnectionId, Seen)>,
+                                         TcpStream, ConnectionId) {
+    let (handler, seen) = Recorder::new(|ctx, message| {
+        ctx.send(message)?;
+        Ok(HandlerAction::Continue)
+    });
+    let server = ServerBuilder::new(handler, RawCodec)
+        .bind("127.0.0.1:0")
+        .with_workers(1)
+        .with_framing(LengthDelimited::default().with_max_frame_len(MAX_FRAME_LEN))
+        .with_oversize_policy(policy)
+        .run()
+        .unwrap();
+
+    let mut client = TcpStream::connect(server.local_addrs()[0]).unwrap();
+    let (id, _) = next(&seen);
+    let bytes = [frame(b"before"), frame(&vec![7u8; OVERSIZED]), frame(b"after")].concat();
+    client.write_all(&bytes).unwrap();
+    assert_eq!(next(&seen), (id, Seen::Message(b"before".to_vec())));
+    assert_eq!(next(&seen), (id, Seen::Oversized(OVERSIZED)));
+    (server, seen, client, id)
+}
+
+#[test]
+fn oversized_frame_closes() {
+    //the unread rest makes the close a reset, the echo may be lost
+    let (server, seen, _client, id) = oversized(OversizePolicy::Close);
+    assert_eq!(next(&seen), (id, Seen::Disconnected(DisconnectReason::ProtocolError)));
+
+    server.shutdown(Duration::from_secs(1));
+    assert_eq!(server.join().unwrap().messages(), 1);
+}
+
+#[test]
+fn oversized_frame_is_answered_by_the_error_frame() {
+    let error = Arc::new(b"too long".to_vec());
+    let (server, seen, mut client, id) = oversized(OversizePolicy::ErrorFrame(error));
+    assert_eq!(next(&seen), (id, Seen::Message(b"after".to_vec())));
+    assert_eq!(read_frame(&mut client), b"before");
+    assert_eq!(read_frame(&mut client), b"too long");
+    assert_eq!(read_frame(&mut client), b"after");
+    drop(client);
+    assert_eq!(next(&seen), (id, Seen::Disconnected(DisconnectReason::PeerClosed)));
+
+    server.shutdown(Duration::from_secs(1));
+    assert_eq!(server.join().unwrap().messages(), 2);
+}
+
+#[test]
+fn oversized_frame_is_discarded() {
+    let (server, seen, mut client, id) = oversized(OversizePolicy::Discard);
+    assert_eq!(next(&seen), (id, Seen::Message(b"after".to_vec())));
+    assert_eq!(read_frame(&mut client), b"before");
+    assert_eq!(read_frame(&mut client), b"after");
+    drop(client);
+    assert_eq!(next(&seen), (id, Seen::Disconnected(DisconnectReason::PeerClosed)));
+
+    server.shutdown(Duration::from_secs(1));
+    assert_eq!(server.join().unwrap().messages(), 2);
+}